@@ -27,7 +27,7 @@ mkdir -p "$OUTPUT_DIR"
 
 # Download JSON
 echo "Fetching data from $ENDPOINT..."
-RESPONSE=$(curl -s "$ENDPOINT")
+RESPONSE=$(curl -s --compressed "$ENDPOINT")
 
 # Check if we're the leader
 IS_LEADER=$(echo "$RESPONSE" | jq -r '.count // 0')
@@ -0,0 +1,69 @@
+//! Optional check that a registering client really listens at its claimed address
+//!
+//! With `verify_client_addr` on, the leader calls `GET http://{addr}/p2p/ping?nonce=...`
+//! on the client's P2P server before accepting `/register`, and the client must
+//! answer `{"nonce": "..."}` with the same value. Without this, a client could
+//! register someone else's ip:port and have image requests sent to them.
+
+use rand::Rng;
+use serde::Deserialize;
+use std::time::Duration;
+
+/// How long the leader waits for the client's ping answer
+const PING_TIMEOUT_SECS: u64 = 3;
+
+#[derive(Deserialize)]
+struct PingResponse {
+    nonce: String,
+}
+
+/// Ping URL for a canonical client address (IPv6 hosts are already bracketed)
+fn ping_url(addr: &str, nonce: &str) -> String {
+    format!("http://{}/p2p/ping?nonce={}", addr, nonce)
+}
+
+/// Ping `addr` with a fresh nonce; `Err` says why the address could not be verified
+pub async fn verify_addr(addr: &str) -> Result<(), String> {
+    let nonce = hex::encode(rand::thread_rng().gen::<[u8; 16]>());
+    let url = ping_url(addr, &nonce);
+
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(PING_TIMEOUT_SECS))
+        .build()
+        .map_err(|e| format!("failed to build HTTP client: {}", e))?;
+
+    let response = client
+        .get(&url)
+        .send()
+        .await
+        .map_err(|e| format!("no answer from {}: {}", addr, e))?;
+    if !response.status().is_success() {
+        return Err(format!("{} answered ping with {}", addr, response.status()));
+    }
+
+    let body: PingResponse = response
+        .json()
+        .await
+        .map_err(|e| format!("bad ping answer from {}: {}", addr, e))?;
+    if body.nonce != nonce {
+        return Err(format!("{} answered with the wrong nonce", addr));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::registration::canonical_addr;
+
+    #[test]
+    fn ping_url_brackets_ipv6_hosts() {
+        let url = ping_url(&canonical_addr("[0:0:0:0:0:0:0:1]:9000"), "abc");
+        assert_eq!(url, "http://[::1]:9000/p2p/ping?nonce=abc");
+
+        let parsed = reqwest::Url::parse(&url).unwrap();
+        assert_eq!(parsed.host_str(), Some("[::1]"));
+        assert_eq!(parsed.port(), Some(9000));
+    }
+}
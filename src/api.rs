@@ -2,26 +2,38 @@
 
 
 
-use crate::registration::ImageStorage;
+use crate::registration::image_storage::content_type_for;
+use crate::registration::quota_manager::{self, QuotaConfig, UsageStats};
+use crate::registration::{ImageStorage, RegistrationError};
 use axum::extract::Multipart;
 use image::ImageFormat;
 
 
 
-use crate::registration::{UserDirectory, UserInfo, ImageNote, NoteStorage};
+use crate::registration::{canonical_addr, UserDirectory, UserInfo, UserStatus, ImageNote, NoteStorage};
+use crate::settings::Settings;
+use crate::signing;
 use crate::NodeState;
 use axum::{
-    extract::State,
-    http::StatusCode,
-    response::{IntoResponse, Json},
-    routing::{get, post},
+    extract::{ConnectInfo, Query, RawQuery, State},
+    middleware,
+    http::{header, HeaderMap, StatusCode},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Json, Response,
+    },
+    routing::{get, post, put},
     Router,
 };
+use futures::future::join_all;
+use futures::stream::{self, StreamExt};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
 use std::time::Instant;
 use tokio::sync::RwLock;
+use tower_http::compression::CompressionLayer;
 use tracing::{info, warn};  // ADD warn here
 use base64::Engine;          // ADD this line
 
@@ -35,19 +47,61 @@ pub struct OnlineClient {
     pub last_heartbeat: Instant,
 }
 
+impl OnlineClient {
+    /// Whether the last heartbeat is at most `ttl_secs` old. The cleanup task
+    /// only runs every few seconds, so readers check this too.
+    pub fn is_fresh(&self, ttl_secs: u64) -> bool {
+        self.last_heartbeat.elapsed().as_secs() <= ttl_secs
+    }
+}
+
 // Shared application state
 #[derive(Clone)]
 pub struct AppState {
     pub user_directory: Arc<UserDirectory>,
     pub node_state: Arc<RwLock<NodeState>>,
     pub online_clients: Arc<RwLock<HashMap<String, OnlineClient>>>,
+    /// Port this node's HTTP API is served on
+    pub http_port: u16,
+    pub settings: Arc<Settings>,
+    /// Upper bound of the randomized election timeout; readiness allows twice this
+    /// since the last leader heartbeat
+    pub election_timeout_max_ms: u64,
+    /// Election address -> HTTP base URL (e.g. "http://10.0.0.1:3000") for every node
+    pub peer_http_urls: Arc<HashMap<String, String>>,
+    /// Responses remembered for `Idempotency-Key` replays
+    pub idempotency_keys: crate::middleware::IdempotencyStore,
+    /// username -> profile metadata and when it was fetched, for `/discover?tag=`
+    pub metadata_cache: MetadataCache,
 }
 
+/// username -> (profile metadata, when it was fetched)
+pub type MetadataCache = Arc<RwLock<HashMap<String, (HashMap<String, String>, Instant)>>>;
+
+/// How long the readiness probe waits for the storage backend
+const READINESS_STORAGE_TIMEOUT_SECS: u64 = 3;
+
+/// Most heartbeats accepted in one `/heartbeat/batch` request
+const MAX_HEARTBEAT_BATCH: usize = 100;
+
+/// How long cached profile metadata is used by tag-filtered discovery
+const METADATA_CACHE_TTL_SECS: u64 = 300;
+
+/// Images returned per user by the discover-with-images endpoints
+const MAX_DISCOVERY_IMAGES_PER_USER: usize = 20;
+
 // Request/Response types
 #[derive(Debug, Deserialize)]
 pub struct RegisterRequest {
     pub username: String,
     pub addr: String,
+    /// hex SHA-256 of the client's signing secret (see `signing`)
+    #[serde(default)]
+    pub key_hash: Option<String>,
+    /// Update an existing registration instead of answering 409; the body
+    /// must be signed with the key already on file
+    #[serde(default)]
+    pub upsert: bool,
 }
 
 #[derive(Debug, Serialize)]
@@ -57,6 +111,39 @@ pub struct RegisterResponse {
     pub user_id: Option<String>,
 }
 
+#[derive(Debug, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum BulkRegisterStatus {
+    Registered,
+    Exists,
+    Error,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BulkRegisterItem {
+    pub username: String,
+    pub status: BulkRegisterStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BulkRegisterResponse {
+    pub success: bool,
+    pub message: String,
+    pub registered: usize,
+    pub results: Vec<BulkRegisterItem>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct WhoamiResponse {
+    pub ip: String,
+    /// Whether `ip` came from X-Forwarded-For rather than the TCP peer
+    pub forwarded: bool,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct HeartbeatRequest {
     pub username: String,
@@ -69,12 +156,78 @@ pub struct HeartbeatResponse {
     pub message: String,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct HeartbeatBatchRequest {
+    pub heartbeats: Vec<HeartbeatRequest>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HeartbeatBatchItem {
+    pub username: String,
+    /// "ok" or "error"
+    pub status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_seen: Option<chrono::DateTime<chrono::Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HeartbeatBatchResponse {
+    pub success: bool,
+    pub message: String,
+    pub results: Vec<HeartbeatBatchItem>,
+}
+
+/// A user as the API shows it: the stored profile without the signing key
+/// hash, which doubles as the HMAC key and must never leave the server
+#[derive(Debug, Serialize)]
+pub struct PublicUser {
+    pub id: String,
+    pub username: String,
+    pub addr: String,
+    pub status: UserStatus,
+    pub registered_at: chrono::DateTime<chrono::Utc>,
+    pub last_seen: chrono::DateTime<chrono::Utc>,
+    pub metadata: HashMap<String, String>,
+    /// Whether the user registered a key_hash (and can sign requests)
+    pub has_signing_key: bool,
+}
+
+impl From<UserInfo> for PublicUser {
+    fn from(mut user: UserInfo) -> Self {
+        let has_signing_key = user.metadata.remove(signing::KEY_HASH_METADATA).is_some();
+        Self {
+            id: user.id,
+            username: user.username,
+            addr: user.addr,
+            status: user.status,
+            registered_at: user.registered_at,
+            last_seen: user.last_seen,
+            metadata: user.metadata,
+            has_signing_key,
+        }
+    }
+}
+
 #[derive(Debug, Serialize)]
 pub struct UserListResponse {
-    pub users: Vec<UserInfo>,
+    pub users: Vec<PublicUser>,
     pub count: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_page_token: Option<String>,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct ListUsersQuery {
+    pub page_token: Option<String>,
+    pub per_page: Option<usize>,
+}
+
+/// Page size for GET /users when only a page_token is given
+const DEFAULT_USERS_PER_PAGE: usize = 20;
+const MAX_USERS_PER_PAGE: usize = 100;
+
 #[derive(Debug, Serialize)]
 pub struct StatusResponse {
     pub status: String,
@@ -82,6 +235,13 @@ pub struct StatusResponse {
     pub is_leader: bool,
     pub current_leader: Option<String>,
     pub online_clients_count: usize,
+    pub http_port: u16,
+    /// Peer election address -> HTTP API port, from the last status poll
+    /// (leader) or `peer_http_urls`; peers with neither are left out
+    pub peer_http_ports: BTreeMap<String, u16>,
+    /// How long this node has known no leader; absent while one is known
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub leaderless_duration_ms: Option<u64>,
 }
 
 
@@ -104,18 +264,78 @@ pub struct ImageUploadResponse {
     pub success: bool,
     pub message: String,
     pub filename: Option<String>,
+    /// Same bytes were already stored; `filename` is the existing image
+    pub was_duplicate: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UserProfileResponse {
+    #[serde(flatten)]
+    pub user: PublicUser,
+    pub is_online: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct QuotaResponse {
+    pub username: String,
+    #[serde(flatten)]
+    pub usage: UsageStats,
+    #[serde(flatten)]
+    pub limits: QuotaConfig,
 }
 
 #[derive(Debug, Serialize)]
 pub struct ImageListResponse {
     pub images: Vec<String>,
     pub count: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_page_token: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ListImagesQuery {
+    pub page_token: Option<String>,
+    pub per_page: Option<usize>,
+    /// Only filenames starting with this
+    pub prefix: Option<String>,
+    /// Only images stored at or after this time
+    pub since: Option<chrono::DateTime<chrono::Utc>>,
 }
 
+/// Page size for image listings when only a page_token or filter is given
+const DEFAULT_IMAGES_PER_PAGE: usize = 20;
+const MAX_IMAGES_PER_PAGE: usize = 100;
+
 #[derive(Debug, Serialize)]
 pub struct ImageWithData {
     pub filename: String,
-    pub data: String,  // base64 encoded
+    /// base64 encoded; left out when REQUIRE_DOWNLOAD_TOKENS is set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<String>,
+    /// Download URL with a fresh token, sent instead of `data` when REQUIRE_DOWNLOAD_TOKENS is set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+}
+
+impl ImageWithData {
+    fn inline(filename: String, data: &[u8]) -> Self {
+        Self {
+            filename,
+            data: Some(base64::engine::general_purpose::STANDARD.encode(data)),
+            url: None,
+        }
+    }
+
+    /// A tokenized `/image` URL in place of the bytes, so discovery doesn't bypass download tokens
+    fn tokenized(username: &str, filename: String) -> Self {
+        let expires_at = chrono::Utc::now().timestamp() + signing::DEFAULT_DOWNLOAD_TOKEN_TTL_SECS as i64;
+        let token = signing::issue_download_token(username, &filename, expires_at);
+        Self {
+            url: Some(format!("/image/{}/{}?token={}", username, filename, token)),
+            filename,
+            data: None,
+        }
+    }
 }
 
 #[derive(Debug, Serialize)]
@@ -131,6 +351,45 @@ pub struct DiscoverWithImagesResponse {
     pub count: usize,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct UpdateSampleImagesRequest {
+    pub sample_images: Vec<String>,  // base64 encoded
+    #[serde(default)]
+    pub append: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UpdateSampleImagesResponse {
+    pub success: bool,
+    pub message: String,
+    pub filenames: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ImageTokenRequest {
+    pub username: String,
+    pub filename: String,
+    #[serde(default)]
+    pub ttl_secs: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ImageTokenResponse {
+    pub success: bool,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DownloadImageQuery {
+    pub token: Option<String>,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct AddNoteRequest {
     pub target_username: String,
@@ -150,37 +409,141 @@ pub struct GetNotesResponse {
     pub count: usize,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct RenameUserRequest {
+    pub old_username: String,
+    pub new_username: String,
+}
+
 #[derive(Debug, Serialize)]
-pub struct NoNotesResponse {
+pub struct RenameUserResponse {
+    pub success: bool,
     pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub files_moved: Option<usize>,
 }
 
 
 
 // Configure routes
 pub fn create_router(state: AppState) -> Router {
-    Router::new()
+    // Discovery responses carry base64 images, so they are gzip/deflate
+    // compressed when the client sends Accept-Encoding
+    let mut discovery = Router::new()
+        .route("/discover", get(discover_online))
+        .route("/discover_with_images", get(discover_with_images));
+
+    // Not compressed: the compression layer would buffer events instead of streaming them
+    let streaming = Router::new().route("/discover_stream", get(discover_stream));
+
+    if !state.settings.disable_compression {
+        discovery = discovery.layer(CompressionLayer::new());
+    } else {
+        info!("Response compression disabled (disable_compression)");
+    }
+
+    let router = Router::new()
         .route("/", get(health_check))
-        .route("/register", post(register_user))
+        .route("/healthz/live", get(liveness_probe))
+        .route("/healthz/ready", get(readiness_probe))
+        .route("/election/metrics", get(election_metrics))
+        .route("/election/latency_histogram", get(election_latency_histogram))
+        .route("/metrics", get(prometheus_metrics))
+        .route("/cluster", get(cluster_status))
+        .route(
+            "/register",
+            post(register_user).layer(middleware::from_fn_with_state(
+                state.clone(),
+                crate::middleware::idempotency,
+            )),
+        )
+        .route("/register/bulk", post(register_users_bulk))
         .route("/heartbeat", post(heartbeat))
+        .route("/heartbeat/batch", post(heartbeat_batch))
         .route("/users", get(list_users))
-        .route("/discover", get(discover_online))
-        .route("/discover_with_images", get(discover_with_images))
+        .route("/users/:username", get(get_user_profile))
+        .route("/whoami", get(whoami))
+        .merge(discovery)
+        .merge(streaming)
         .route("/upload_image/:username", post(upload_image))
         .route("/images/:username", get(list_user_images))
+        .route("/users/:username/quota", get(get_user_quota))
+        .route("/users/:username/images", get(list_user_images))
+        .route("/users/:username/images/:filename", get(get_user_image))
+        .route("/users/:username/images/:filename/thumbnail", get(get_user_image_thumbnail))
         .route("/image/:username/:filename", get(download_image))
+        .route("/image_token", post(issue_image_token))
+        .route("/user/:username/sample/:index", get(download_sample_image))
+        .route("/users/:username/sample-images", put(update_sample_images))
         .route("/add_note", post(add_note))              // NEW
         .route("/get_note/:username", get(get_notes))    // NEW
-        .with_state(state)
+        .route("/admin/rename-user", post(rename_user));
+
+    let router = if state.settings.require_request_signatures {
+        info!("Request signatures required for POST/PUT/DELETE (REQUIRE_REQUEST_SIGNATURES)");
+        router.route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            signing::verify_request_signature,
+        ))
+    } else {
+        router
+    };
+
+    // Outermost, so followers redirect writes before doing any other work
+    let router = if state.peer_http_urls.is_empty() {
+        router
+    } else {
+        router.layer(middleware::from_fn_with_state(
+            state.clone(),
+            crate::middleware::follower_redirect,
+        ))
+    };
+
+    router.with_state(state)
 }
 
+/// Response for a failed storage call: Firebase quota hits become 503 with
+/// `Retry-After`, everything else gets `fallback`
+pub(crate) fn storage_error_response(
+    e: &RegistrationError,
+    fallback: StatusCode,
+    body: impl IntoResponse,
+) -> Response {
+    match e {
+        RegistrationError::QuotaExceeded { retry_after_secs } => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(header::RETRY_AFTER, retry_after_secs.to_string())],
+            body,
+        )
+            .into_response(),
+        _ => (fallback, body).into_response(),
+    }
+}
 
 // Health check endpoint
 async fn health_check(State(state): State<AppState>) -> impl IntoResponse {
     let ns = state.node_state.read().await;
     let is_leader = ns.state == crate::State::Leader;
     let current_leader = ns.leader.clone();
+    let leaderless_duration_ms = ns
+        .leaderless_since
+        .filter(|_| ns.leader.is_none())
+        .map(|since| since.elapsed().as_millis() as u64);
     
+    let mut peer_http_ports: BTreeMap<String, u16> = state
+        .peer_http_urls
+        .iter()
+        .filter_map(|(addr, url)| {
+            let port = reqwest::Url::parse(url).ok()?.port_or_known_default()?;
+            Some((addr.clone(), port))
+        })
+        .collect();
+    peer_http_ports.extend(
+        ns.cluster_status
+            .iter()
+            .filter_map(|peer| Some((peer.addr.clone(), peer.http_port?))),
+    );
+
     let online_count = state.online_clients.read().await.len();
     
     Json(StatusResponse {
@@ -189,14 +552,153 @@ async fn health_check(State(state): State<AppState>) -> impl IntoResponse {
         is_leader,
         current_leader,
         online_clients_count: online_count,
+        http_port: state.http_port,
+        peer_http_ports,
+        leaderless_duration_ms,
     })
 }
 
+// Liveness probe (any node): fails only if node state can't be read
+async fn liveness_probe(State(state): State<AppState>) -> impl IntoResponse {
+    match tokio::time::timeout(
+        std::time::Duration::from_secs(1),
+        state.node_state.read(),
+    )
+    .await
+    {
+        Ok(_) => (StatusCode::OK, Json(serde_json::json!({ "status": "alive" }))),
+        Err(_) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(serde_json::json!({ "status": "unhealthy", "reason": "node_state_lock_timeout" })),
+        ),
+    }
+}
+
+// Readiness probe (any node): leader, or follower with a recent leader heartbeat,
+// and the storage backend answers within a few seconds
+async fn readiness_probe(State(state): State<AppState>) -> impl IntoResponse {
+    let not_ready = |reason: &str| {
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(serde_json::json!({ "ready": false, "reason": reason })),
+        )
+    };
+
+    let (is_leader, has_leader, last_heartbeat) = {
+        let ns = state.node_state.read().await;
+        (
+            ns.state == crate::State::Leader,
+            ns.leader.is_some(),
+            ns.last_heartbeat,
+        )
+    };
+
+    if !is_leader {
+        let Some(last_heartbeat) = last_heartbeat.filter(|_| has_leader) else {
+            return not_ready("awaiting_election");
+        };
+        let window = std::time::Duration::from_millis(2 * state.election_timeout_max_ms);
+        if last_heartbeat.elapsed() > window {
+            return not_ready("leader_heartbeat_stale");
+        }
+    }
+
+    match tokio::time::timeout(
+        std::time::Duration::from_secs(READINESS_STORAGE_TIMEOUT_SECS),
+        state.user_directory.check_storage(),
+    )
+    .await
+    {
+        Ok(Ok(())) => (
+            StatusCode::OK,
+            Json(serde_json::json!({ "ready": true, "is_leader": is_leader })),
+        ),
+        Ok(Err(e)) => {
+            warn!("Readiness storage check failed: {}", e);
+            not_ready("storage_unavailable")
+        }
+        Err(_) => not_ready("storage_timeout"),
+    }
+}
+
+// Election metrics endpoint (any node)
+async fn election_metrics(State(state): State<AppState>) -> impl IntoResponse {
+    let metrics = state.node_state.read().await.metrics.clone();
+    Json(metrics)
+}
+
+// Election message round-trip times per peer (any node; each records what it sent)
+async fn election_latency_histogram(State(state): State<AppState>) -> impl IntoResponse {
+    let peers = state.node_state.read().await.peer_latency.clone();
+    Json(serde_json::json!({
+        "bucket_bounds_ms": crate::LATENCY_BUCKET_BOUNDS_MS,
+        "peers": peers,
+    }))
+}
+
+// Cluster summary endpoint - ONLY LEADER CAN PROCESS
+// This node first, then every peer as of the leader's last status poll
+async fn cluster_status(State(state): State<AppState>) -> Response {
+    let ns = state.node_state.read().await;
+
+    if ns.state != crate::State::Leader {
+        return (
+            StatusCode::FORBIDDEN,
+            Json(serde_json::json!({
+                "message": format!(
+                    "This node is not the leader. Current leader: {}",
+                    ns.leader.clone().unwrap_or_else(|| "unknown".to_string())
+                )
+            })),
+        )
+            .into_response();
+    }
+
+    let mut nodes = Vec::with_capacity(ns.cluster_status.len() + 1);
+    nodes.push(crate::PeerStatus {
+        addr: ns.leader.clone().unwrap_or_default(),
+        state: Some(ns.state.clone()),
+        term: Some(ns.current_term),
+        leader: ns.leader.clone(),
+        http_port: Some(state.http_port),
+        reachable: true,
+    });
+    nodes.extend(ns.cluster_status.iter().cloned());
+
+    (StatusCode::OK, Json(nodes)).into_response()
+}
+
+// Prometheus scrape endpoint (any node)
+async fn prometheus_metrics(State(state): State<AppState>) -> impl IntoResponse {
+    let metrics = state.node_state.read().await.metrics.clone();
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        metrics.to_prometheus(),
+    )
+}
+
 // Register endpoint - ONLY LEADER CAN PROCESS
 async fn register_user(
     State(state): State<AppState>,
-    Json(payload): Json<RegisterRequest>,
-) -> impl IntoResponse {
+    headers: HeaderMap,
+    body: axum::body::Bytes,
+) -> Response {
+    // Parsed by hand: an upsert's signature covers the raw body
+    let payload: RegisterRequest = match serde_json::from_slice(&body) {
+        Ok(payload) => payload,
+        Err(e) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(RegisterResponse {
+                    success: false,
+                    message: format!("Invalid register request: {}", e),
+                    user_id: None,
+                }),
+            )
+                .into_response();
+        }
+    };
+
     // Check if this node is the leader
     let (is_leader, leader_addr) = {
         let ns = state.node_state.read().await;
@@ -215,7 +717,8 @@ async fn register_user(
                 ),
                 user_id: None,
             }),
-        );
+        )
+            .into_response();
     }
 
     // Process registration (only if leader)
@@ -227,11 +730,13 @@ async fn register_user(
     // CHECK: Username must be unique in Google Drive
     info!("Checking if username '{}' already exists...", payload.username);
     
-    let all_users = match state.user_directory.list_users().await {
-        Ok(users) => users,
+    // Single profile lookup rather than listing every user
+    let existing_user = match state.user_directory.find_user_by_username(&payload.username).await {
+        Ok(user) => user,
         Err(e) => {
-            tracing::error!("Failed to list users for uniqueness check: {}", e);
-            return (
+            tracing::error!("Failed to look up username for uniqueness check: {}", e);
+            return storage_error_response(
+                &e,
                 StatusCode::INTERNAL_SERVER_ERROR,
                 Json(RegisterResponse {
                     success: false,
@@ -243,7 +748,10 @@ async fn register_user(
     };
 
     // Check if username already exists
-    if all_users.iter().any(|u| u.username == payload.username) {
+    if let Some(existing) = existing_user {
+        if payload.upsert {
+            return upsert_user(&state, &headers, &body, existing, payload).await;
+        }
         info!("Registration rejected: username '{}' already exists", payload.username);
         return (
             StatusCode::CONFLICT,
@@ -252,13 +760,42 @@ async fn register_user(
                 message: format!("Username '{}' is already registered", payload.username),
                 user_id: None,
             }),
-        );
+        )
+            .into_response();
     }
 
     info!("Username '{}' is available, proceeding with registration", payload.username);
 
     // Create and register the new user
-    let user = UserInfo::new(payload.username, payload.addr);
+    let user = match user_from_request(payload) {
+        Ok(user) => user,
+        Err(message) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(RegisterResponse {
+                    success: false,
+                    message,
+                    user_id: None,
+                }),
+            )
+                .into_response();
+        }
+    };
+
+    if state.settings.verify_client_addr {
+        if let Err(reason) = crate::addr_check::verify_addr(&user.addr).await {
+            info!("Registration rejected: could not verify '{}' owns {}: {}", user.username, user.addr, reason);
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(RegisterResponse {
+                    success: false,
+                    message: format!("Could not verify address {}: {}", user.addr, reason),
+                    user_id: None,
+                }),
+            )
+                .into_response();
+        }
+    }
 
     match state.user_directory.register_user(&user).await {
         Ok(_) => {
@@ -274,10 +811,12 @@ async fn register_user(
                     user_id: Some(user.id.clone()),
                 }),
             )
+                .into_response()
         }
         Err(e) => {
             tracing::error!("Registration failed: {}", e);
-            (
+            storage_error_response(
+                &e,
                 StatusCode::BAD_REQUEST,
                 Json(RegisterResponse {
                     success: false,
@@ -289,14 +828,135 @@ async fn register_user(
     }
 }
 
+/// `/register` with `upsert: true` for a user that already exists: proves
+/// ownership with `X-Request-Signature` over the body, made with the key_hash
+/// on file, then replaces the stored addr (and key_hash, if a new one is sent).
+async fn upsert_user(
+    state: &AppState,
+    headers: &HeaderMap,
+    body: &[u8],
+    mut existing: UserInfo,
+    payload: RegisterRequest,
+) -> Response {
+    let reject = |status: StatusCode, message: String| {
+        info!("Upsert rejected for '{}': {}", existing.username, message);
+        (
+            status,
+            Json(RegisterResponse {
+                success: false,
+                message,
+                user_id: None,
+            }),
+        )
+            .into_response()
+    };
 
+    let Some(key) = existing
+        .metadata
+        .get(signing::KEY_HASH_METADATA)
+        .and_then(|key_hash| hex::decode(key_hash).ok())
+    else {
+        return reject(
+            StatusCode::FORBIDDEN,
+            format!(
+                "User '{}' was registered without a key_hash, so ownership can't be proven",
+                existing.username
+            ),
+        );
+    };
+    let now = chrono::Utc::now().timestamp();
+    if let Err(message) = signing::check_request(headers, &key, &existing.username, body, now) {
+        return reject(StatusCode::UNAUTHORIZED, format!("Upsert not authorized: {}", message));
+    }
 
-// Heartbeat endpoint - ONLY LEADER CAN PROCESS
-async fn heartbeat(
+    let update = match user_from_request(payload) {
+        Ok(update) => update,
+        Err(message) => return reject(StatusCode::BAD_REQUEST, message),
+    };
+    if state.settings.verify_client_addr {
+        if let Err(reason) = crate::addr_check::verify_addr(&update.addr).await {
+            return reject(
+                StatusCode::BAD_REQUEST,
+                format!("Could not verify address {}: {}", update.addr, reason),
+            );
+        }
+    }
+
+    existing.addr = update.addr;
+    existing.metadata.extend(update.metadata);
+    existing.last_seen = chrono::Utc::now();
+
+    if let Err(e) = state.user_directory.update_user(&existing).await {
+        tracing::error!("Upsert failed: {}", e);
+        return storage_error_response(
+            &e,
+            StatusCode::BAD_REQUEST,
+            Json(RegisterResponse {
+                success: false,
+                message: format!("Update failed: {}", e),
+                user_id: None,
+            }),
+        );
+    }
+
+    // Discovery shouldn't keep handing out the old address or metadata
+    if let Some(client) = state.online_clients.write().await.get_mut(&existing.username) {
+        client.addr = existing.addr.clone();
+    }
+    state.metadata_cache.write().await.remove(&existing.username);
+
+    info!("Updated registration for '{}' at {}", existing.username, existing.addr);
+    (
+        StatusCode::OK,
+        Json(RegisterResponse {
+            success: true,
+            message: format!(
+                "User '{}' updated at {}",
+                existing.username, existing.addr
+            ),
+            user_id: Some(existing.id.clone()),
+        }),
+    )
+        .into_response()
+}
+
+/// Build the profile for a register request, checking the optional key_hash
+fn user_from_request(payload: RegisterRequest) -> Result<UserInfo, String> {
+    let mut user = UserInfo::new(payload.username, canonical_addr(&payload.addr));
+
+    if let Some(key_hash) = payload.key_hash {
+        if !signing::is_valid_key_hash(&key_hash) {
+            return Err("key_hash must be a hex-encoded SHA-256 (64 hex characters)".to_string());
+        }
+        user = user.with_metadata(signing::KEY_HASH_METADATA, key_hash.to_lowercase());
+    }
+
+    Ok(user)
+}
+
+// Bulk register endpoint - ONLY LEADER CAN PROCESS, requires X-Admin-Token
+//
+// Imports users (e.g. restoring a backup) in one call. Each entry is validated
+// and registered on its own; duplicates are reported as "exists" rather than
+// failing the batch.
+async fn register_users_bulk(
     State(state): State<AppState>,
-    Json(payload): Json<HeartbeatRequest>,
-) -> impl IntoResponse {
-    // Check if this node is the leader
+    headers: HeaderMap,
+    Json(payload): Json<Vec<RegisterRequest>>,
+) -> Response {
+    if !admin_authorized(&state.settings, &headers) {
+        return (
+            StatusCode::FORBIDDEN,
+            Json(BulkRegisterResponse {
+                success: false,
+                message: "Missing or invalid X-Admin-Token".to_string(),
+                registered: 0,
+                results: vec![],
+            }),
+        )
+            .into_response();
+    }
+
     let (is_leader, leader_addr) = {
         let ns = state.node_state.read().await;
         (ns.state == crate::State::Leader, ns.leader.clone())
@@ -305,22 +965,181 @@ async fn heartbeat(
     if !is_leader {
         return (
             StatusCode::FORBIDDEN,
-            Json(HeartbeatResponse {
+            Json(BulkRegisterResponse {
                 success: false,
                 message: format!(
                     "This node is not the leader. Current leader: {}",
                     leader_addr.unwrap_or_else(|| "unknown".to_string())
                 ),
+                registered: 0,
+                results: vec![],
             }),
-        );
+        )
+            .into_response();
     }
 
-    // Update heartbeat timestamp + addr
-    let username = payload.username.clone();
-    let addr = payload.addr.clone();
-
-    let mut online = state.online_clients.write().await;
-    
+    let total = payload.len();
+    let mut results = Vec::with_capacity(total);
+    let mut quota_error = None;
+
+    for request in payload {
+        let username = request.username.clone();
+        let user = match user_from_request(request) {
+            Ok(user) => user,
+            Err(message) => {
+                results.push(BulkRegisterItem {
+                    username,
+                    status: BulkRegisterStatus::Error,
+                    user_id: None,
+                    message: Some(message),
+                });
+                continue;
+            }
+        };
+
+        // register_user validates and checks for an existing profile itself
+        let item = match state.user_directory.register_user(&user).await {
+            Ok(_) => BulkRegisterItem {
+                username,
+                status: BulkRegisterStatus::Registered,
+                user_id: Some(user.id.clone()),
+                message: None,
+            },
+            Err(RegistrationError::UserAlreadyExists(_)) => BulkRegisterItem {
+                username,
+                status: BulkRegisterStatus::Exists,
+                user_id: None,
+                message: None,
+            },
+            Err(e @ RegistrationError::QuotaExceeded { .. }) => {
+                // Every remaining write would be throttled too; stop here
+                quota_error = Some(e);
+                break;
+            }
+            Err(e) => BulkRegisterItem {
+                username,
+                status: BulkRegisterStatus::Error,
+                user_id: None,
+                message: Some(e.to_string()),
+            },
+        };
+        results.push(item);
+    }
+
+    let registered = results
+        .iter()
+        .filter(|item| item.status == BulkRegisterStatus::Registered)
+        .count();
+    info!("Bulk registration: {} of {} users registered", registered, total);
+
+    let response = BulkRegisterResponse {
+        success: quota_error.is_none(),
+        message: format!("Registered {} of {} users", registered, total),
+        registered,
+        results,
+    };
+
+    match quota_error {
+        Some(e) => storage_error_response(&e, StatusCode::SERVICE_UNAVAILABLE, Json(response)),
+        None => (StatusCode::OK, Json(response)).into_response(),
+    }
+}
+
+/// The caller's IP: the TCP peer, unless the peer is a trusted proxy, in which
+/// case X-Forwarded-For is walked right to left past any further trusted proxies
+fn client_ip(peer: SocketAddr, headers: &HeaderMap, trusted_proxies: &[IpAddr]) -> (IpAddr, bool) {
+    let peer_ip = peer.ip().to_canonical();
+    if !trusted_proxies.contains(&peer_ip) {
+        return (peer_ip, false);
+    }
+
+    let forwarded = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|entry| entry.trim().parse::<IpAddr>().ok())
+        .collect::<Vec<_>>();
+
+    match forwarded
+        .iter()
+        .rev()
+        .find(|ip| !trusted_proxies.contains(ip))
+        .or(forwarded.first())
+    {
+        Some(ip) => (*ip, true),
+        None => (peer_ip, false),
+    }
+}
+
+// Whoami endpoint - ONLY LEADER CAN PROCESS
+//
+// Lets a client fill in its own IP for /register and /heartbeat and only
+// supply the port.
+async fn whoami(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> Response {
+    let (is_leader, leader_addr) = {
+        let ns = state.node_state.read().await;
+        (ns.state == crate::State::Leader, ns.leader.clone())
+    };
+
+    if !is_leader {
+        return (
+            StatusCode::FORBIDDEN,
+            Json(serde_json::json!({
+                "message": format!(
+                    "This node is not the leader. Current leader: {}",
+                    leader_addr.unwrap_or_else(|| "unknown".to_string())
+                ),
+            })),
+        )
+            .into_response();
+    }
+
+    let (ip, forwarded) = client_ip(peer, &headers, &state.settings.trusted_proxies);
+    (
+        StatusCode::OK,
+        Json(WhoamiResponse {
+            ip: ip.to_string(),
+            forwarded,
+        }),
+    )
+        .into_response()
+}
+
+// Heartbeat endpoint - ONLY LEADER CAN PROCESS
+async fn heartbeat(
+    State(state): State<AppState>,
+    Json(payload): Json<HeartbeatRequest>,
+) -> impl IntoResponse {
+    // Check if this node is the leader
+    let (is_leader, leader_addr) = {
+        let ns = state.node_state.read().await;
+        (ns.state == crate::State::Leader, ns.leader.clone())
+    };
+
+    if !is_leader {
+        return (
+            StatusCode::FORBIDDEN,
+            Json(HeartbeatResponse {
+                success: false,
+                message: format!(
+                    "This node is not the leader. Current leader: {}",
+                    leader_addr.unwrap_or_else(|| "unknown".to_string())
+                ),
+            }),
+        );
+    }
+
+    // Update heartbeat timestamp + addr
+    let username = payload.username.clone();
+    let addr = canonical_addr(&payload.addr);
+
+    let mut online = state.online_clients.write().await;
+    
     online.insert(
         username.clone(),
         OnlineClient {
@@ -346,11 +1165,130 @@ async fn heartbeat(
     )
 }
 
+// Batch heartbeat endpoint - ONLY LEADER CAN PROCESS
+//
+// For a proxy or harness running several clients: each entry is handled like
+// POST /heartbeat, under one write lock on the online table. With request
+// signing on, the request is signed as X-Username and may only heartbeat that user.
+async fn heartbeat_batch(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(payload): Json<HeartbeatBatchRequest>,
+) -> impl IntoResponse {
+    let (is_leader, leader_addr) = {
+        let ns = state.node_state.read().await;
+        (ns.state == crate::State::Leader, ns.leader.clone())
+    };
+
+    if !is_leader {
+        return (
+            StatusCode::FORBIDDEN,
+            Json(HeartbeatBatchResponse {
+                success: false,
+                message: format!(
+                    "This node is not the leader. Current leader: {}",
+                    leader_addr.unwrap_or_else(|| "unknown".to_string())
+                ),
+                results: vec![],
+            }),
+        );
+    }
+
+    if payload.heartbeats.len() > MAX_HEARTBEAT_BATCH {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(HeartbeatBatchResponse {
+                success: false,
+                message: format!(
+                    "Too many heartbeats: {} (max {})",
+                    payload.heartbeats.len(),
+                    MAX_HEARTBEAT_BATCH
+                ),
+                results: vec![],
+            }),
+        );
+    }
+
+    let signer = state
+        .settings
+        .require_request_signatures
+        .then(|| {
+            headers
+                .get(signing::USERNAME_HEADER)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string)
+        })
+        .flatten();
+
+    let now = Instant::now();
+    let last_seen = chrono::Utc::now();
+    let mut online = state.online_clients.write().await;
+
+    let results: Vec<HeartbeatBatchItem> = payload
+        .heartbeats
+        .into_iter()
+        .map(|heartbeat| {
+            let rejection = if heartbeat.username.is_empty() {
+                Some("Username cannot be empty".to_string())
+            } else if state.settings.require_request_signatures
+                && signer.as_deref() != Some(heartbeat.username.as_str())
+            {
+                Some("A signed batch may only heartbeat the signing user".to_string())
+            } else {
+                None
+            };
+
+            if let Some(message) = rejection {
+                return HeartbeatBatchItem {
+                    username: heartbeat.username,
+                    status: "error",
+                    last_seen: None,
+                    message: Some(message),
+                };
+            }
+
+            online.insert(
+                heartbeat.username.clone(),
+                OnlineClient {
+                    username: heartbeat.username.clone(),
+                    addr: canonical_addr(&heartbeat.addr),
+                    last_heartbeat: now,
+                },
+            );
+            HeartbeatBatchItem {
+                username: heartbeat.username,
+                status: "ok",
+                last_seen: Some(last_seen),
+                message: None,
+            }
+        })
+        .collect();
+
+    let accepted = results.iter().filter(|item| item.status == "ok").count();
+    info!(
+        "Batch heartbeat: {} of {} accepted (total online: {})",
+        accepted,
+        results.len(),
+        online.len()
+    );
+
+    (
+        StatusCode::OK,
+        Json(HeartbeatBatchResponse {
+            success: true,
+            message: format!("Accepted {} of {} heartbeats", accepted, results.len()),
+            results,
+        }),
+    )
+}
 
 // List users endpoint - ONLY LEADER CAN PROCESS
-async fn list_users(State(state): State<AppState>) -> impl IntoResponse {
+async fn list_users(
+    State(state): State<AppState>,
+    Query(query): Query<ListUsersQuery>,
+) -> Response {
     // Check if this node is the leader
-    let (is_leader, leader_addr) = {
+    let (is_leader, _) = {
         let ns = state.node_state.read().await;
         (ns.state == crate::State::Leader, ns.leader.clone())
     };
@@ -362,33 +1300,190 @@ async fn list_users(State(state): State<AppState>) -> impl IntoResponse {
             Json(UserListResponse {
                 users: vec![],
                 count: 0,
+                next_page_token: None,
             }),
-        );
+        )
+            .into_response();
     }
 
-    match state.user_directory.list_users().await {
-        Ok(users) => {
+    // Without paging parameters keep returning the full list
+    let result = if query.page_token.is_none() && query.per_page.is_none() {
+        state.user_directory.list_users().await.map(|users| (users, None))
+    } else {
+        let per_page = query
+            .per_page
+            .unwrap_or(DEFAULT_USERS_PER_PAGE)
+            .clamp(1, MAX_USERS_PER_PAGE);
+        state
+            .user_directory
+            .list_users_paginated(query.page_token, per_page)
+            .await
+    };
+
+    match result {
+        Ok((users, next_page_token)) => {
             let count = users.len();
             (
                 StatusCode::OK,
-                Json(UserListResponse { users, count }),
+                Json(UserListResponse {
+                    users: users.into_iter().map(PublicUser::from).collect(),
+                    count,
+                    next_page_token,
+                }),
             )
+                .into_response()
         }
         Err(e) => {
             tracing::error!("Failed to list users: {}", e);
-            (
+            storage_error_response(
+                &e,
                 StatusCode::INTERNAL_SERVER_ERROR,
                 Json(UserListResponse {
                     users: vec![],
                     count: 0,
+                    next_page_token: None,
                 }),
             )
         }
     }
 }
 
+// User profile endpoint - ONLY LEADER CAN PROCESS
+// Online status comes from the in-memory heartbeat table, not Firebase
+async fn get_user_profile(
+    State(state): State<AppState>,
+    axum::extract::Path(username): axum::extract::Path<String>,
+) -> Response {
+    let (is_leader, leader_addr) = {
+        let ns = state.node_state.read().await;
+        (ns.state == crate::State::Leader, ns.leader.clone())
+    };
+
+    if !is_leader {
+        return (
+            StatusCode::FORBIDDEN,
+            Json(serde_json::json!({
+                "message": format!(
+                    "This node is not the leader. Current leader: {}",
+                    leader_addr.unwrap_or_else(|| "unknown".to_string())
+                )
+            })),
+        )
+            .into_response();
+    }
+
+    let mut user = match state.user_directory.get_user(&username).await {
+        Ok(user) => user,
+        Err(RegistrationError::UserNotFound(_)) => {
+            return (
+                StatusCode::NOT_FOUND,
+                Json(serde_json::json!({
+                    "message": format!("User not found: {}", username)
+                })),
+            )
+                .into_response();
+        }
+        Err(e) => {
+            tracing::error!("Failed to load user '{}': {}", username, e);
+            return storage_error_response(
+                &e,
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(serde_json::json!({
+                    "message": format!("Failed to load user: {}", e)
+                })),
+            );
+        }
+    };
+
+    let is_online = match state.online_clients.read().await.get(&username) {
+        Some(client) => {
+            let elapsed = client.last_heartbeat.elapsed();
+            // The heartbeat table is fresher than the persisted last_seen
+            if let Ok(elapsed) = chrono::Duration::from_std(elapsed) {
+                user.last_seen = chrono::Utc::now() - elapsed;
+            }
+            client.is_fresh(state.settings.heartbeat_ttl_secs)
+        }
+        None => false,
+    };
+
+    (StatusCode::OK, Json(UserProfileResponse { user: user.into(), is_online })).into_response()
+}
+
+/// `/discover` query parameters
+#[derive(Debug, Default)]
+struct DiscoveryFilter {
+    /// Lowercased; keeps usernames containing it
+    q: Option<String>,
+    /// Answer `{"online_count": N}` instead of the client list
+    status_only: bool,
+    /// Repeated `tag=key=value`, all must match the profile metadata
+    tags: Vec<(String, String)>,
+}
+
+/// Parse `q`, `status_only` and repeated `tag=key=value` query parameters
+fn parse_discovery_filter(query: Option<&str>) -> Result<DiscoveryFilter, String> {
+    let mut filter = DiscoveryFilter::default();
+    let Some(query) = query else {
+        return Ok(filter);
+    };
+
+    for (name, value) in form_urlencoded::parse(query.as_bytes()) {
+        match name.as_ref() {
+            "tag" => match value.split_once('=') {
+                Some((key, tag_value)) if !key.is_empty() => {
+                    filter.tags.push((key.to_string(), tag_value.to_string()))
+                }
+                _ => return Err(format!("tag must be key=value, got '{}'", value)),
+            },
+            "q" if !value.is_empty() => filter.q = Some(value.to_lowercase()),
+            "status_only" => {
+                filter.status_only = match value.as_ref() {
+                    "true" | "1" => true,
+                    "false" | "0" => false,
+                    _ => return Err(format!("status_only must be true or false, got '{}'", value)),
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(filter)
+}
+
+/// Profile metadata for an online user, from the cache while it is fresh.
+/// `None` for users without a profile or whose profile couldn't be loaded.
+async fn cached_metadata(state: &AppState, username: &str) -> Option<HashMap<String, String>> {
+    if let Some((metadata, fetched)) = state.metadata_cache.read().await.get(username) {
+        if fetched.elapsed().as_secs() < METADATA_CACHE_TTL_SECS {
+            return Some(metadata.clone());
+        }
+    }
+
+    match state.user_directory.get_user(username).await {
+        Ok(user) => {
+            state
+                .metadata_cache
+                .write()
+                .await
+                .insert(username.to_string(), (user.metadata.clone(), Instant::now()));
+            Some(user.metadata)
+        }
+        Err(RegistrationError::UserNotFound(_)) => None,
+        Err(e) => {
+            warn!("Failed to load metadata for user '{}': {}", username, e);
+            None
+        }
+    }
+}
+
 // Discovery endpoint - ONLY LEADER CAN PROCESS
-async fn discover_online(State(state): State<AppState>) -> impl IntoResponse {
+// `?q=` matches usernames (case-insensitive substring), `?tag=key=value`
+// (repeatable, all must match) filters by profile metadata, and
+// `?status_only=true` answers just the count
+async fn discover_online(
+    State(state): State<AppState>,
+    RawQuery(query): RawQuery,
+) -> Response {
     // Check if this node is the leader
     let (is_leader, _leader_addr) = {
         let ns = state.node_state.read().await;
@@ -404,32 +1499,87 @@ async fn discover_online(State(state): State<AppState>) -> impl IntoResponse {
                 count: 0,
                 is_leader: false,
             }),
-        );
+        )
+            .into_response();
     }
 
+    let filter = match parse_discovery_filter(query.as_deref()) {
+        Ok(filter) => filter,
+        Err(message) => {
+            info!("Discovery request rejected: {}", message);
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(DiscoveryResponse {
+                    online_clients: vec![],
+                    count: 0,
+                    is_leader: true,
+                }),
+            )
+                .into_response();
+        }
+    };
+
     // Return currently online clients with username + addr
-    let online = state.online_clients.read().await;
-    let online_list: Vec<DiscoveryClient> = online
+    let mut online_list: Vec<DiscoveryClient> = state
+        .online_clients
+        .read()
+        .await
         .values()
+        .filter(|client| client.is_fresh(state.settings.heartbeat_ttl_secs))
+        .filter(|client| {
+            filter
+                .q
+                .as_ref()
+                .is_none_or(|q| client.username.to_lowercase().contains(q.as_str()))
+        })
         .map(|client| DiscoveryClient {
             username: client.username.clone(),
             addr: client.addr.clone(),
         })
         .collect();
 
+    // Metadata is only loaded when a tag filter needs it, status_only included
+    let tags = filter.tags;
+    if !tags.is_empty() {
+        let metadata = join_all(
+            online_list
+                .iter()
+                .map(|client| cached_metadata(&state, &client.username)),
+        )
+        .await;
+
+        // The signing key hash is never matchable, so it can't be probed through discovery
+        let matches = |metadata: &HashMap<String, String>| {
+            tags.iter().all(|(key, value)| {
+                key != signing::KEY_HASH_METADATA && metadata.get(key) == Some(value)
+            })
+        };
+        let mut metadata = metadata.into_iter();
+        online_list.retain(|_| metadata.next().flatten().is_some_and(|m| matches(&m)));
+    }
+
     info!(
         "Discovery request served: {} clients online",
         online_list.len()
     );
 
+    if filter.status_only {
+        return (
+            StatusCode::OK,
+            Json(serde_json::json!({ "online_count": online_list.len() })),
+        )
+            .into_response();
+    }
+
     (
         StatusCode::OK,
         Json(DiscoveryResponse {
+            count: online_list.len(),
             online_clients: online_list,
-            count: online.len(),
             is_leader: true,
         }),
     )
+        .into_response()
 }
 
 // Upload image endpoint - ONLY LEADER CAN PROCESS
@@ -437,7 +1587,7 @@ async fn upload_image(
     State(state): State<AppState>,
     axum::extract::Path(username): axum::extract::Path<String>,
     mut multipart: Multipart,
-) -> impl IntoResponse {
+) -> Response {
     // Check if this node is the leader
     let (is_leader, leader_addr) = {
         let ns = state.node_state.read().await;
@@ -454,8 +1604,10 @@ async fn upload_image(
                     leader_addr.unwrap_or_else(|| "unknown".to_string())
                 ),
                 filename: None,
+                was_duplicate: false,
             }),
-        );
+        )
+            .into_response();
     }
 
     // Extract image data from multipart
@@ -486,45 +1638,62 @@ async fn upload_image(
                 success: false,
                 message: "No image data provided".to_string(),
                 filename: None,
+                was_duplicate: false,
             }),
-        );
+        )
+            .into_response();
     };
 
     // Upload image
     let image_storage = ImageStorage::new(&state.user_directory);
     
     match image_storage.upload_image(&username, data, format).await {
-        Ok(filename) => {
-            info!("Image uploaded for user '{}': {}", username, filename);
+        Ok(result) => {
+            info!("Image uploaded for user '{}': {}", username, result.filename);
+            // A duplicate created nothing, so it isn't a 201
+            let (status, message) = if result.was_duplicate {
+                (StatusCode::OK, "Image already uploaded")
+            } else {
+                (StatusCode::CREATED, "Image uploaded successfully")
+            };
             (
-                StatusCode::CREATED,
+                status,
                 Json(ImageUploadResponse {
                     success: true,
-                    message: format!("Image uploaded successfully"),
-                    filename: Some(filename),
+                    message: message.to_string(),
+                    filename: Some(result.filename),
+                    was_duplicate: result.was_duplicate,
                 }),
             )
+                .into_response()
         }
         Err(e) => {
             tracing::error!("Image upload failed: {}", e);
-            (
-                StatusCode::BAD_REQUEST,
+            let status = if quota_manager::is_quota_exceeded(&e) {
+                StatusCode::TOO_MANY_REQUESTS
+            } else {
+                StatusCode::BAD_REQUEST
+            };
+            storage_error_response(
+                &e,
+                status,
                 Json(ImageUploadResponse {
                     success: false,
                     message: format!("Upload failed: {}", e),
                     filename: None,
+                    was_duplicate: false,
                 }),
             )
         }
     }
 }
 
-// List images endpoint - ONLY LEADER CAN PROCESS
-async fn list_user_images(
+// Quota endpoint - ONLY LEADER CAN PROCESS
+async fn get_user_quota(
     State(state): State<AppState>,
     axum::extract::Path(username): axum::extract::Path<String>,
-) -> impl IntoResponse {
-    let (is_leader, _) = {
+) -> Response {
+    let (is_leader, leader_addr) = {
         let ns = state.node_state.read().await;
         (ns.state == crate::State::Leader, ns.leader.clone())
     };
@@ -532,30 +1701,112 @@ async fn list_user_images(
     if !is_leader {
         return (
             StatusCode::FORBIDDEN,
-            Json(ImageListResponse {
-                images: vec![],
-                count: 0,
-            }),
-        );
+            Json(serde_json::json!({
+                "message": format!(
+                    "This node is not the leader. Current leader: {}",
+                    leader_addr.unwrap_or_else(|| "unknown".to_string())
+                ),
+            })),
+        )
+            .into_response();
     }
 
     let image_storage = ImageStorage::new(&state.user_directory);
-    
-    match image_storage.list_images(&username).await {
-        Ok(images) => {
-            let count = images.len();
-            (
-                StatusCode::OK,
-                Json(ImageListResponse { images, count }),
-            )
-        }
-        Err(e) => {
-            tracing::error!("Failed to list images: {}", e);
+
+    match image_storage.get_usage(&username).await {
+        Ok(usage) => (
+            StatusCode::OK,
+            Json(QuotaResponse {
+                username,
+                usage,
+                limits: state.user_directory.quota().clone(),
+            }),
+        )
+            .into_response(),
+        Err(e) => {
+            let status = match e {
+                RegistrationError::UserNotFound(_) => StatusCode::NOT_FOUND,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            storage_error_response(
+                &e,
+                status,
+                Json(serde_json::json!({ "message": format!("Failed to get usage: {}", e) })),
+            )
+        }
+    }
+}
+
+// List images endpoint - ONLY LEADER CAN PROCESS
+async fn list_user_images(
+    State(state): State<AppState>,
+    axum::extract::Path(username): axum::extract::Path<String>,
+    Query(query): Query<ListImagesQuery>,
+) -> Response {
+    let (is_leader, _) = {
+        let ns = state.node_state.read().await;
+        (ns.state == crate::State::Leader, ns.leader.clone())
+    };
+
+    if !is_leader {
+        return (
+            StatusCode::FORBIDDEN,
+            Json(ImageListResponse {
+                images: vec![],
+                count: 0,
+                next_page_token: None,
+            }),
+        )
+            .into_response();
+    }
+
+    let image_storage = ImageStorage::new(&state.user_directory);
+
+    // Without paging parameters or filters keep returning the full list
+    let unpaged = query.page_token.is_none()
+        && query.per_page.is_none()
+        && query.prefix.is_none()
+        && query.since.is_none();
+    let result = if unpaged {
+        image_storage.list_images(&username).await.map(|images| (images, None))
+    } else {
+        let per_page = query
+            .per_page
+            .unwrap_or(DEFAULT_IMAGES_PER_PAGE)
+            .clamp(1, MAX_IMAGES_PER_PAGE);
+        image_storage
+            .list_images_paginated(
+                &username,
+                query.prefix.as_deref(),
+                query.since,
+                query.page_token,
+                per_page,
+            )
+            .await
+    };
+
+    match result {
+        Ok((images, next_page_token)) => {
+            let count = images.len();
             (
+                StatusCode::OK,
+                Json(ImageListResponse {
+                    images,
+                    count,
+                    next_page_token,
+                }),
+            )
+                .into_response()
+        }
+        Err(e) => {
+            tracing::error!("Failed to list images: {}", e);
+            storage_error_response(
+                &e,
                 StatusCode::INTERNAL_SERVER_ERROR,
                 Json(ImageListResponse {
                     images: vec![],
                     count: 0,
+                    next_page_token: None,
                 }),
             )
         }
@@ -563,31 +1814,345 @@ async fn list_user_images(
 }
 
 // Download image endpoint - ONLY LEADER CAN PROCESS
+// A `?token=` from /image_token is checked when given, and required with REQUIRE_DOWNLOAD_TOKENS
 async fn download_image(
     State(state): State<AppState>,
     axum::extract::Path((username, filename)): axum::extract::Path<(String, String)>,
-) -> impl IntoResponse {
+    Query(query): Query<DownloadImageQuery>,
+) -> Result<Vec<u8>, Response> {
     let (is_leader, _) = {
         let ns = state.node_state.read().await;
         (ns.state == crate::State::Leader, ns.leader.clone())
     };
 
     if !is_leader {
-        return Err((StatusCode::FORBIDDEN, "Not leader".to_string()));
+        return Err((StatusCode::FORBIDDEN, "Not leader".to_string()).into_response());
     }
 
+    check_download_token(&state, &query, &username, &filename).map_err(IntoResponse::into_response)?;
+
     let image_storage = ImageStorage::new(&state.user_directory);
     
     match image_storage.download_image(&username, &filename).await {
         Ok(data) => Ok(data),
-        Err(e) => Err((StatusCode::NOT_FOUND, format!("Image not found: {}", e))),
+        Err(e) => Err(storage_error_response(
+            &e,
+            StatusCode::NOT_FOUND,
+            format!("Image not found: {}", e),
+        )),
+    }
+}
+
+/// Check a download's `?token=`: verified when given, required with REQUIRE_DOWNLOAD_TOKENS
+fn check_download_token(
+    state: &AppState,
+    query: &DownloadImageQuery,
+    username: &str,
+    filename: &str,
+) -> Result<(), (StatusCode, String)> {
+    match &query.token {
+        Some(token) => {
+            if let Err(reason) = signing::verify_download_token(token, username, filename) {
+                info!("Rejected download of {}/{}: {}", username, filename, reason);
+                return Err((StatusCode::FORBIDDEN, reason));
+            }
+        }
+        None if state.settings.require_download_tokens => {
+            return Err((StatusCode::UNAUTHORIZED, "Download token required".to_string()));
+        }
+        None => {}
+    }
+    Ok(())
+}
+
+// Image bytes with Content-Type, for UIs that show images directly - ONLY LEADER CAN PROCESS
+async fn get_user_image(
+    State(state): State<AppState>,
+    axum::extract::Path((username, filename)): axum::extract::Path<(String, String)>,
+    Query(query): Query<DownloadImageQuery>,
+) -> Result<Response, Response> {
+    let is_leader = state.node_state.read().await.state == crate::State::Leader;
+    if !is_leader {
+        return Err((StatusCode::FORBIDDEN, "Not leader".to_string()).into_response());
+    }
+
+    check_download_token(&state, &query, &username, &filename).map_err(IntoResponse::into_response)?;
+
+    let image_storage = ImageStorage::new(&state.user_directory);
+
+    match image_storage.download_image(&username, &filename).await {
+        Ok(data) => Ok(([(header::CONTENT_TYPE, content_type_for(&filename))], data).into_response()),
+        Err(e) => Err(storage_error_response(
+            &e,
+            StatusCode::NOT_FOUND,
+            format!("Image not found: {}", e),
+        )),
+    }
+}
+
+// Thumbnail (or the full image if there is none) - ONLY LEADER CAN PROCESS
+async fn get_user_image_thumbnail(
+    State(state): State<AppState>,
+    axum::extract::Path((username, filename)): axum::extract::Path<(String, String)>,
+    Query(query): Query<DownloadImageQuery>,
+) -> Result<Response, Response> {
+    let is_leader = state.node_state.read().await.state == crate::State::Leader;
+    if !is_leader {
+        return Err((StatusCode::FORBIDDEN, "Not leader".to_string()).into_response());
+    }
+
+    check_download_token(&state, &query, &username, &filename).map_err(IntoResponse::into_response)?;
+
+    let image_storage = ImageStorage::new(&state.user_directory);
+
+    match image_storage.download_thumbnail(&username, &filename).await {
+        Ok(data) => Ok(([(header::CONTENT_TYPE, content_type_for(&filename))], data).into_response()),
+        Err(e) => Err(storage_error_response(
+            &e,
+            StatusCode::NOT_FOUND,
+            format!("Image not found: {}", e),
+        )),
+    }
+}
+
+// Download token endpoint - ONLY LEADER CAN PROCESS
+// Issues a short-lived token for one image; covered by request signing when enabled
+async fn issue_image_token(
+    State(state): State<AppState>,
+    Json(payload): Json<ImageTokenRequest>,
+) -> Response {
+    let (is_leader, leader_addr) = {
+        let ns = state.node_state.read().await;
+        (ns.state == crate::State::Leader, ns.leader.clone())
+    };
+
+    if !is_leader {
+        return (
+            StatusCode::FORBIDDEN,
+            Json(ImageTokenResponse {
+                success: false,
+                message: format!(
+                    "This node is not the leader. Current leader: {}",
+                    leader_addr.unwrap_or_else(|| "unknown".to_string())
+                ),
+                token: None,
+                url: None,
+                expires_at: None,
+            }),
+        )
+            .into_response();
+    }
+
+    let image_storage = ImageStorage::new(&state.user_directory);
+
+    let exists = match image_storage.list_images(&payload.username).await {
+        Ok(filenames) => filenames.contains(&payload.filename),
+        Err(RegistrationError::UserNotFound(_)) => false,
+        Err(e) => {
+            tracing::error!("Failed to list images for token: {}", e);
+            return storage_error_response(
+                &e,
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(ImageTokenResponse {
+                    success: false,
+                    message: format!("Failed to issue token: {}", e),
+                    token: None,
+                    url: None,
+                    expires_at: None,
+                }),
+            );
+        }
+    };
+
+    if !exists {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(ImageTokenResponse {
+                success: false,
+                message: format!("Image not found: {}/{}", payload.username, payload.filename),
+                token: None,
+                url: None,
+                expires_at: None,
+            }),
+        )
+            .into_response();
+    }
+
+    let ttl_secs = payload
+        .ttl_secs
+        .unwrap_or(signing::DEFAULT_DOWNLOAD_TOKEN_TTL_SECS)
+        .clamp(1, signing::MAX_DOWNLOAD_TOKEN_TTL_SECS);
+    let expires_at = chrono::Utc::now().timestamp() + ttl_secs as i64;
+    let token = signing::issue_download_token(&payload.username, &payload.filename, expires_at);
+
+    info!(
+        "Issued download token for {}/{} (expires in {}s)",
+        payload.username, payload.filename, ttl_secs
+    );
+
+    (
+        StatusCode::OK,
+        Json(ImageTokenResponse {
+            success: true,
+            message: format!("Token valid for {}s", ttl_secs),
+            url: Some(format!(
+                "/image/{}/{}?token={}",
+                payload.username, payload.filename, token
+            )),
+            token: Some(token),
+            expires_at: Some(expires_at),
+        }),
+    )
+        .into_response()
+}
+
+// Sample image endpoint - ONLY LEADER CAN PROCESS
+// Serves the raw bytes of the image at `index` in the same order discover_with_images uses,
+// so clients can fetch thumbnails lazily instead of decoding base64 from JSON
+async fn download_sample_image(
+    State(state): State<AppState>,
+    axum::extract::Path((username, index)): axum::extract::Path<(String, usize)>,
+    Query(query): Query<DownloadImageQuery>,
+) -> Result<Response, Response> {
+    let (is_leader, _) = {
+        let ns = state.node_state.read().await;
+        (ns.state == crate::State::Leader, ns.leader.clone())
+    };
+
+    if !is_leader {
+        return Err((StatusCode::FORBIDDEN, "Not leader".to_string()).into_response());
+    }
+
+    let image_storage = ImageStorage::new(&state.user_directory);
+
+    let filenames = match image_storage.list_images(&username).await {
+        Ok(filenames) => filenames,
+        Err(RegistrationError::UserNotFound(_)) => {
+            return Err((StatusCode::NOT_FOUND, format!("User not found: {}", username)).into_response());
+        }
+        Err(e) => {
+            tracing::error!("Failed to list images: {}", e);
+            return Err(storage_error_response(
+                &e,
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to list images: {}", e),
+            ));
+        }
+    };
+
+    let Some(filename) = filenames.get(index) else {
+        return Err((
+            StatusCode::NOT_FOUND,
+            format!("No sample image at index {} for user '{}'", index, username),
+        )
+            .into_response());
+    };
+
+    check_download_token(&state, &query, &username, filename).map_err(IntoResponse::into_response)?;
+
+    match image_storage.download_image(&username, filename).await {
+        Ok(data) => Ok(([(header::CONTENT_TYPE, content_type_for(filename))], data).into_response()),
+        Err(e) => Err(storage_error_response(
+            &e,
+            StatusCode::NOT_FOUND,
+            format!("Image not found: {}", e),
+        )),
+    }
+}
+
+// Update sample images endpoint - ONLY LEADER CAN PROCESS
+async fn update_sample_images(
+    State(state): State<AppState>,
+    axum::extract::Path(username): axum::extract::Path<String>,
+    Json(payload): Json<UpdateSampleImagesRequest>,
+) -> Response {
+    let (is_leader, leader_addr) = {
+        let ns = state.node_state.read().await;
+        (ns.state == crate::State::Leader, ns.leader.clone())
+    };
+
+    if !is_leader {
+        return (
+            StatusCode::FORBIDDEN,
+            Json(UpdateSampleImagesResponse {
+                success: false,
+                message: format!(
+                    "This node is not the leader. Current leader: {}",
+                    leader_addr.unwrap_or_else(|| "unknown".to_string())
+                ),
+                filenames: vec![],
+            }),
+        )
+            .into_response();
+    }
+
+    // Decode base64 up front so a malformed entry is reported by index
+    let mut images = Vec::with_capacity(payload.sample_images.len());
+    for (index, encoded) in payload.sample_images.iter().enumerate() {
+        match base64::engine::general_purpose::STANDARD.decode(encoded) {
+            Ok(data) => images.push(data),
+            Err(e) => {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    Json(UpdateSampleImagesResponse {
+                        success: false,
+                        message: format!("Image {} is not valid base64: {}", index, e),
+                        filenames: vec![],
+                    }),
+                )
+                    .into_response();
+            }
+        }
+    }
+
+    let image_storage = ImageStorage::new(&state.user_directory);
+
+    match image_storage
+        .update_sample_images(&username, images, payload.append)
+        .await
+    {
+        Ok(filenames) => {
+            info!(
+                "Sample images updated for user '{}': {} (append={})",
+                username,
+                filenames.len(),
+                payload.append
+            );
+            (
+                StatusCode::OK,
+                Json(UpdateSampleImagesResponse {
+                    success: true,
+                    message: format!("Updated sample images for '{}'", username),
+                    filenames,
+                }),
+            )
+                .into_response()
+        }
+        Err(e) => {
+            tracing::error!("Sample image update failed: {}", e);
+            let status = match e {
+                RegistrationError::UserNotFound(_) => StatusCode::NOT_FOUND,
+                _ if quota_manager::is_quota_exceeded(&e) => StatusCode::TOO_MANY_REQUESTS,
+                RegistrationError::ValidationError(_) => StatusCode::BAD_REQUEST,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            storage_error_response(
+                &e,
+                status,
+                Json(UpdateSampleImagesResponse {
+                    success: false,
+                    message: format!("Update failed: {}", e),
+                    filenames: vec![],
+                }),
+            )
+        }
     }
 }
 
 // Discover with images endpoint - ONLY LEADER CAN PROCESS
 async fn discover_with_images(State(state): State<AppState>) -> impl IntoResponse {
     // Check if this node is the leader
-    let (is_leader, leader_addr) = {
+    let (is_leader, _) = {
         let ns = state.node_state.read().await;
         (ns.state == crate::State::Leader, ns.leader.clone())
     };
@@ -607,6 +2172,7 @@ async fn discover_with_images(State(state): State<AppState>) -> impl IntoRespons
     let online = state.online_clients.read().await;
     let online_usernames: Vec<(String, String)> = online
         .values()
+        .filter(|client| client.is_fresh(state.settings.heartbeat_ttl_secs))
         .map(|client| (client.username.clone(), client.addr.clone()))
         .collect();
     drop(online); // Release lock
@@ -616,54 +2182,78 @@ async fn discover_with_images(State(state): State<AppState>) -> impl IntoRespons
         online_usernames.len()
     );
 
+    // Batch-fetch profiles so only registered users are queried for images
+    let usernames: Vec<String> = online_usernames
+        .iter()
+        .map(|(username, _)| username.clone())
+        .collect();
+    let user_results = state.user_directory.batch_get_users(&usernames).await;
+
+    let registered: Vec<String> = usernames
+        .iter()
+        .filter(|username| match user_results.get(*username) {
+            Some(Ok(_)) => true,
+            Some(Err(e)) => {
+                warn!("Failed to load profile for user '{}': {}", username, e);
+                false
+            }
+            None => false,
+        })
+        .cloned()
+        .collect();
+
     let image_storage = ImageStorage::new(&state.user_directory);
-    let mut clients_with_images = Vec::new();
-
-    // For each online client, fetch their images
-    for (username, addr) in online_usernames {
-        let mut images_data = Vec::new();
-
-        // List images for this user
-        match image_storage.list_images(&username).await {
-            Ok(image_filenames) => {
-                // Limit to 20 images per user
-                let limited_filenames: Vec<_> = image_filenames.into_iter().take(20).collect();
-
-                info!(
-                    "Fetching {} images for user '{}'",
-                    limited_filenames.len(),
-                    username
-                );
-
-                // Download each image and base64 encode
-                for filename in limited_filenames {
-                    match image_storage.download_image(&username, &filename).await {
-                        Ok(data) => {
-                            // Base64 encode
-                            let encoded = base64::engine::general_purpose::STANDARD.encode(&data);
-                            images_data.push(ImageWithData {
-                                filename,
-                                data: encoded,
-                            });
-                        }
-                        Err(e) => {
-                            warn!("Failed to download image {}/{}: {}", username, filename, e);
-                        }
-                    }
+    let mut image_lists = image_storage.batch_list_images(&registered).await;
+
+    // Limit images per user, then download everything in one batch
+    let mut to_download: Vec<(String, String)> = Vec::new();
+    for username in &registered {
+        if let Some(filenames) = image_lists.remove(username) {
+            info!(
+                "Fetching {} images for user '{}'",
+                filenames.len().min(MAX_DISCOVERY_IMAGES_PER_USER),
+                username
+            );
+            to_download.extend(
+                filenames
+                    .into_iter()
+                    .take(MAX_DISCOVERY_IMAGES_PER_USER)
+                    .map(|filename| (username.clone(), filename)),
+            );
+        }
+    }
+
+    let mut images_by_user: HashMap<String, Vec<ImageWithData>> = HashMap::new();
+    if state.settings.require_download_tokens {
+        for (username, filename) in to_download {
+            let image = ImageWithData::tokenized(&username, filename);
+            images_by_user.entry(username).or_default().push(image);
+        }
+    } else {
+        let downloads = image_storage.batch_download_images(&to_download).await;
+        for ((username, filename), result) in to_download.into_iter().zip(downloads) {
+            match result {
+                Ok(data) => {
+                    images_by_user
+                        .entry(username)
+                        .or_default()
+                        .push(ImageWithData::inline(filename, &data));
+                }
+                Err(e) => {
+                    warn!("Failed to download image {}/{}: {}", username, filename, e);
                 }
-            }
-            Err(e) => {
-                warn!("Failed to list images for user '{}': {}", username, e);
-                // Continue with empty images for this user
             }
         }
+    }
 
-        clients_with_images.push(OnlineClientWithImages {
-            username: username.clone(),
+    let clients_with_images: Vec<OnlineClientWithImages> = online_usernames
+        .into_iter()
+        .map(|(username, addr)| OnlineClientWithImages {
+            images: images_by_user.remove(&username).unwrap_or_default(),
+            username,
             addr,
-            images: images_data,
-        });
-    }
+        })
+        .collect();
 
     let count = clients_with_images.len();
     info!(
@@ -680,11 +2270,108 @@ async fn discover_with_images(State(state): State<AppState>) -> impl IntoRespons
     )
 }
 
+/// Images for one online client, as sent in `user_data` events. Unregistered
+/// users and failed downloads just contribute no images.
+async fn fetch_client_images(state: &AppState, username: &str) -> Vec<ImageWithData> {
+    let image_storage = ImageStorage::new(&state.user_directory);
+
+    let filenames = match image_storage.list_images(username).await {
+        Ok(filenames) => filenames,
+        Err(RegistrationError::UserNotFound(_)) => return vec![],
+        Err(e) => {
+            warn!("Failed to list images for user '{}': {}", username, e);
+            return vec![];
+        }
+    };
+
+    let to_download: Vec<(String, String)> = filenames
+        .into_iter()
+        .take(MAX_DISCOVERY_IMAGES_PER_USER)
+        .map(|filename| (username.to_string(), filename))
+        .collect();
+    if state.settings.require_download_tokens {
+        return to_download
+            .into_iter()
+            .map(|(_, filename)| ImageWithData::tokenized(username, filename))
+            .collect();
+    }
+    let downloads = image_storage.batch_download_images(&to_download).await;
+
+    to_download
+        .into_iter()
+        .zip(downloads)
+        .filter_map(|((_, filename), result)| match result {
+            Ok(data) => Some(ImageWithData::inline(filename, &data)),
+            Err(e) => {
+                warn!("Failed to download image {}/{}: {}", username, filename, e);
+                None
+            }
+        })
+        .collect()
+}
+
+// Streaming discover with images - ONLY LEADER CAN PROCESS
+//
+// Same data as /discover_with_images, but sent as server-sent events: one
+// `user_data` event per online client as soon as its images are fetched,
+// then `done` with the total count.
+async fn discover_stream(State(state): State<AppState>) -> Response {
+    let is_leader = {
+        let ns = state.node_state.read().await;
+        ns.state == crate::State::Leader
+    };
+
+    if !is_leader {
+        info!("Discover stream request rejected - not leader");
+        return (
+            StatusCode::FORBIDDEN,
+            Json(DiscoverWithImagesResponse {
+                online_clients: vec![],
+                count: 0,
+            }),
+        )
+            .into_response();
+    }
+
+    let online_clients: Vec<(String, String)> = state
+        .online_clients
+        .read()
+        .await
+        .values()
+        .filter(|client| client.is_fresh(state.settings.heartbeat_ttl_secs))
+        .map(|client| (client.username.clone(), client.addr.clone()))
+        .collect();
+
+    let count = online_clients.len();
+    info!("Discover stream request: {} clients online", count);
+
+    let user_events = stream::iter(online_clients).then(move |(username, addr)| {
+        let state = state.clone();
+        async move {
+            let client = OnlineClientWithImages {
+                images: fetch_client_images(&state, &username).await,
+                username,
+                addr,
+            };
+            Event::default().event("user_data").json_data(&client)
+        }
+    });
+    let done = stream::once(async move {
+        Event::default()
+            .event("done")
+            .json_data(serde_json::json!({ "count": count }))
+    });
+
+    Sse::new(user_events.chain(done))
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
+
 // Add note endpoint - ONLY LEADER CAN PROCESS
 async fn add_note(
     State(state): State<AppState>,
     Json(payload): Json<AddNoteRequest>,
-) -> impl IntoResponse {
+) -> Response {
     // Check if this node is the leader
     let (is_leader, leader_addr) = {
         let ns = state.node_state.read().await;
@@ -701,7 +2388,8 @@ async fn add_note(
                     leader_addr.unwrap_or_else(|| "unknown".to_string())
                 ),
             }),
-        );
+        )
+            .into_response();
     }
 
     let note_storage = NoteStorage::new(&state.user_directory);
@@ -729,10 +2417,12 @@ async fn add_note(
                     ),
                 }),
             )
+                .into_response()
         }
         Err(e) => {
             tracing::error!("Failed to add note: {}", e);
-            (
+            storage_error_response(
+                &e,
                 StatusCode::BAD_REQUEST,
                 Json(AddNoteResponse {
                     success: false,
@@ -788,13 +2478,655 @@ async fn get_notes(
         }
         Err(e) => {
             tracing::error!("Failed to get notes: {}", e);
-            (
+            storage_error_response(
+                &e,
                 StatusCode::BAD_REQUEST,
                 Json(serde_json::json!({
                     "message": format!("Failed to get notes: {}", e)
                 })),
             )
+        }
+    }
+}
+
+/// Check `X-Admin-Token` against ADMIN_TOKEN. Admin endpoints are closed when
+/// no token is configured; the comparison doesn't short-circuit on the first
+/// differing byte.
+fn admin_authorized(settings: &Settings, headers: &HeaderMap) -> bool {
+    let Some(expected) = settings.admin_token.as_deref() else {
+        return false;
+    };
+    let Some(given) = headers.get("x-admin-token").and_then(|v| v.to_str().ok()) else {
+        return false;
+    };
+    given.len() == expected.len()
+        && given
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0u8, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+// Rename user endpoint - ONLY LEADER CAN PROCESS, requires X-Admin-Token
+async fn rename_user(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(payload): Json<RenameUserRequest>,
+) -> Response {
+    if !admin_authorized(&state.settings, &headers) {
+        return (
+            StatusCode::FORBIDDEN,
+            Json(RenameUserResponse {
+                success: false,
+                message: "Missing or invalid X-Admin-Token".to_string(),
+                files_moved: None,
+            }),
+        )
+            .into_response();
+    }
+
+    let (is_leader, leader_addr) = {
+        let ns = state.node_state.read().await;
+        (ns.state == crate::State::Leader, ns.leader.clone())
+    };
+
+    if !is_leader {
+        return (
+            StatusCode::FORBIDDEN,
+            Json(RenameUserResponse {
+                success: false,
+                message: format!(
+                    "This node is not the leader. Current leader: {}",
+                    leader_addr.unwrap_or_else(|| "unknown".to_string())
+                ),
+                files_moved: None,
+            }),
+        )
+            .into_response();
+    }
+
+    match state
+        .user_directory
+        .rename_user(&payload.old_username, &payload.new_username)
+        .await
+    {
+        Ok(result) => {
+            // Keep the renamed user online without waiting for the next heartbeat
+            let mut clients = state.online_clients.write().await;
+            if let Some(mut client) = clients.remove(&payload.old_username) {
+                client.username = payload.new_username.clone();
+                clients.insert(payload.new_username.clone(), client);
+            }
+            drop(clients);
+            state.metadata_cache.write().await.remove(&payload.old_username);
+
+            (
+                StatusCode::OK,
+                Json(RenameUserResponse {
+                    success: true,
+                    message: format!(
+                        "Renamed '{}' to '{}'",
+                        payload.old_username, payload.new_username
+                    ),
+                    files_moved: Some(result.files_moved),
+                }),
+            )
                 .into_response()
         }
+        Err(e) => {
+            tracing::error!("Rename failed: {}", e);
+            let status = match e {
+                RegistrationError::UserNotFound(_) => StatusCode::NOT_FOUND,
+                RegistrationError::UserAlreadyExists(_) => StatusCode::CONFLICT,
+                RegistrationError::ValidationError(_) => StatusCode::BAD_REQUEST,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            storage_error_response(
+                &e,
+                status,
+                Json(RenameUserResponse {
+                    success: false,
+                    message: format!("Rename failed: {}", e),
+                    files_moved: None,
+                }),
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::registration::test_util;
+    use axum::body::{to_bytes, Body};
+    use axum::http::{Method, Request};
+    use tower::ServiceExt;
+
+    /// 64 hex characters, as a client's SHA-256 key hash would be
+    const KEY_HASH: &str = "00112233445566778899aabbccddeeff00112233445566778899aabbccddeeff";
+
+    /// App state over an in-memory directory, as leader unless `leader` is false
+    fn test_state(leader: bool, settings: Settings) -> AppState {
+        let snapshot_path = std::env::temp_dir()
+            .join(format!("node-snapshot-{}.json", uuid::Uuid::new_v4()))
+            .to_string_lossy()
+            .into_owned();
+        let mut node_state = NodeState::new(snapshot_path, None);
+        if leader {
+            node_state.state = crate::State::Leader;
+            node_state.leader = Some("127.0.0.1:5000".to_string());
+        }
+
+        AppState {
+            user_directory: Arc::new(test_util::directory()),
+            node_state: Arc::new(RwLock::new(node_state)),
+            online_clients: Arc::new(RwLock::new(HashMap::new())),
+            http_port: 3000,
+            settings: Arc::new(settings),
+            election_timeout_max_ms: 5000,
+            peer_http_urls: Arc::new(HashMap::new()),
+            idempotency_keys: Arc::new(RwLock::new(HashMap::new())),
+            metadata_cache: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    async fn send(state: &AppState, request: Request<Body>) -> Response {
+        create_router(state.clone()).oneshot(request).await.unwrap()
+    }
+
+    async fn body_json(response: Response) -> serde_json::Value {
+        let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    fn get(uri: &str) -> Request<Body> {
+        Request::get(uri).body(Body::empty()).unwrap()
+    }
+
+    fn post_json(uri: &str, body: &serde_json::Value) -> Request<Body> {
+        Request::post(uri)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+
+    async fn register_with_key(state: &AppState, username: &str) {
+        let user = UserInfo::new(username, "127.0.0.1:9000")
+            .with_metadata(signing::KEY_HASH_METADATA, KEY_HASH);
+        state.user_directory.register_user(&user).await.unwrap();
+    }
+
+    /// `method uri` with `body`, signed by `username` at `timestamp`
+    fn signed(method: Method, uri: &str, username: &str, body: &serde_json::Value, timestamp: i64) -> Request<Body> {
+        let key = hex::decode(KEY_HASH).unwrap();
+        let body = body.to_string();
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(signing::TIMESTAMP_HEADER, timestamp.to_string())
+            .header(
+                signing::SIGNATURE_HEADER,
+                signing::sign(&key, username, timestamp, body.as_bytes()),
+            )
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    fn signing_settings() -> Settings {
+        Settings {
+            require_request_signatures: true,
+            ..Settings::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn signature_for_another_users_path_is_forbidden() {
+        let state = test_state(true, signing_settings());
+        register_with_key(&state, "alice").await;
+        register_with_key(&state, "bob").await;
+
+        let body = serde_json::json!({ "username": "alice", "sample_images": [] });
+        let now = chrono::Utc::now().timestamp();
+        let response = send(&state, signed(Method::PUT, "/users/bob/sample-images", "alice", &body, now)).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn stale_signature_is_rejected() {
+        let state = test_state(true, signing_settings());
+        register_with_key(&state, "alice").await;
+
+        let body = serde_json::json!({ "username": "alice", "sample_images": [] });
+        let stale = chrono::Utc::now().timestamp() - signing::MAX_SIGNATURE_AGE_SECS - 60;
+        let response = send(&state, signed(Method::PUT, "/users/alice/sample-images", "alice", &body, stale)).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let now = chrono::Utc::now().timestamp();
+        let response = send(&state, signed(Method::PUT, "/users/alice/sample-images", "alice", &body, now)).await;
+        assert_ne!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_ne!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn upsert_updates_user_but_plain_register_is_rejected() {
+        let state = test_state(true, Settings::default());
+        register_with_key(&state, "alice").await;
+
+        let plain = serde_json::json!({ "username": "alice", "addr": "127.0.0.1:9100" });
+        let response = send(&state, post_json("/register", &plain)).await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let user = state.user_directory.find_user_by_username("alice").await.unwrap().unwrap();
+        assert_eq!(user.addr, "127.0.0.1:9000");
+
+        let upsert = serde_json::json!({ "username": "alice", "addr": "127.0.0.1:9100", "upsert": true });
+        let now = chrono::Utc::now().timestamp();
+        let response = send(&state, signed(Method::POST, "/register", "alice", &upsert, now)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let user = state.user_directory.find_user_by_username("alice").await.unwrap().unwrap();
+        assert_eq!(user.addr, "127.0.0.1:9100");
+    }
+
+    #[tokio::test]
+    async fn status_and_cluster_report_peer_http_ports() {
+        let state = test_state(true, Settings::default());
+        state.node_state.write().await.cluster_status = vec![crate::PeerStatus {
+            addr: "127.0.0.1:5002".to_string(),
+            state: Some(crate::State::Follower),
+            term: Some(1),
+            leader: Some("127.0.0.1:5000".to_string()),
+            http_port: Some(3002),
+            reachable: true,
+        }];
+
+        let cluster = body_json(send(&state, get("/cluster")).await).await;
+        assert_eq!(cluster[0]["http_port"], 3000);
+        assert_eq!(cluster[1]["addr"], "127.0.0.1:5002");
+        assert_eq!(cluster[1]["http_port"], 3002);
+
+        let status = body_json(send(&state, get("/")).await).await;
+        assert_eq!(status["peer_http_ports"]["127.0.0.1:5002"], 3002);
+    }
+
+    /// Mark `username` online with a heartbeat `age_secs` ago
+    async fn add_online(state: &AppState, username: &str, age_secs: u64) {
+        let client = OnlineClient {
+            username: username.to_string(),
+            addr: "127.0.0.1:9000".to_string(),
+            last_heartbeat: Instant::now() - std::time::Duration::from_secs(age_secs),
+        };
+        state.online_clients.write().await.insert(username.to_string(), client);
+    }
+
+    #[tokio::test]
+    async fn large_discovery_response_is_gzipped() {
+        let state = test_state(true, Settings::default());
+        for i in 0..200 {
+            add_online(&state, &format!("user{:03}", i), 0).await;
+        }
+
+        let request = Request::get("/discover")
+            .header(header::ACCEPT_ENCODING, "gzip")
+            .body(Body::empty())
+            .unwrap();
+        let response = send(&state, request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "gzip");
+
+        let compressed = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let mut json = String::new();
+        std::io::Read::read_to_string(&mut flate2::read::GzDecoder::new(&compressed[..]), &mut json).unwrap();
+        assert!(compressed.len() < json.len());
+        let body: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(body["count"], 200);
+    }
+
+    #[tokio::test]
+    async fn sample_image_binary_matches_uploaded_base64() {
+        let state = test_state(true, Settings::default());
+        test_util::register(&state.user_directory, "alice").await;
+        let encoded = base64::engine::general_purpose::STANDARD.encode(test_util::png(3, 16));
+
+        let update = serde_json::json!({ "sample_images": [encoded], "append": false });
+        let request = Request::put("/users/alice/sample-images")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(update.to_string()))
+            .unwrap();
+        assert_eq!(send(&state, request).await.status(), StatusCode::OK);
+
+        let response = send(&state, get("/user/alice/sample/0")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "image/png");
+        let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let expected = base64::engine::general_purpose::STANDARD.decode(&encoded).unwrap();
+        assert_eq!(bytes.as_ref(), expected.as_slice());
+
+        let response = send(&state, get("/user/alice/sample/1")).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn required_download_tokens_cover_samples_and_discovery() {
+        let settings = Settings {
+            require_download_tokens: true,
+            ..Settings::default()
+        };
+        let state = test_state(true, settings);
+        test_util::register(&state.user_directory, "alice").await;
+        let filename = ImageStorage::new(&state.user_directory)
+            .upload_image("alice", test_util::png(3, 16), image::ImageFormat::Png)
+            .await
+            .unwrap()
+            .filename;
+        add_online(&state, "alice", 0).await;
+
+        let response = send(&state, get("/user/alice/sample/0")).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let token = signing::issue_download_token("alice", &filename, chrono::Utc::now().timestamp() + 60);
+        let response = send(&state, get(&format!("/user/alice/sample/0?token={}", token))).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // Discovery hands out tokenized URLs instead of the bytes
+        let body = body_json(send(&state, get("/discover_with_images")).await).await;
+        let image = &body["online_clients"][0]["images"][0];
+        assert_eq!(image["filename"], filename.as_str());
+        assert!(image.get("data").is_none());
+        let url = image["url"].as_str().unwrap();
+        assert_eq!(send(&state, get(url)).await.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn oversized_sample_image_gets_400_naming_it() {
+        let state = test_state(true, Settings::default());
+        test_util::register(&state.user_directory, "alice").await;
+        let images: Vec<String> = [test_util::png(1, 16), test_util::png(2, 256)]
+            .iter()
+            .map(|png| base64::engine::general_purpose::STANDARD.encode(png))
+            .collect();
+
+        let update = serde_json::json!({ "sample_images": images });
+        let request = Request::put("/users/alice/sample-images")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(update.to_string()))
+            .unwrap();
+        let response = send(&state, request).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let message = body_json(response).await["message"].as_str().unwrap().to_string();
+        assert!(message.contains("Image 1 is too large: 256x256"), "{}", message);
+    }
+
+    #[tokio::test]
+    async fn discovery_excludes_clients_past_heartbeat_ttl() {
+        let settings = Settings {
+            heartbeat_ttl_secs: 30,
+            ..Settings::default()
+        };
+        let state = test_state(true, settings);
+        add_online(&state, "fresh", 5).await;
+        add_online(&state, "stale", 120).await;
+
+        let body = body_json(send(&state, get("/discover")).await).await;
+        assert_eq!(body["count"], 1);
+        assert_eq!(body["online_clients"][0]["username"], "fresh");
+    }
+
+    #[tokio::test]
+    async fn bulk_register_reports_each_user() {
+        let settings = Settings {
+            admin_token: Some("admin-secret".to_string()),
+            ..Settings::default()
+        };
+        let state = test_state(true, settings);
+        test_util::register(&state.user_directory, "alice").await;
+
+        let users = serde_json::json!([
+            { "username": "alice", "addr": "127.0.0.1:9001" },
+            { "username": "bob", "addr": "127.0.0.1:9002" },
+            { "username": "carol", "addr": "[::1]:9003" },
+        ]);
+        let request = Request::post("/register/bulk")
+            .header(header::CONTENT_TYPE, "application/json")
+            .header("X-Admin-Token", "admin-secret")
+            .body(Body::from(users.to_string()))
+            .unwrap();
+        let response = send(&state, request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = body_json(response).await;
+        assert_eq!(body["registered"], 2);
+        let statuses: Vec<(&str, &str)> = body["results"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|item| (item["username"].as_str().unwrap(), item["status"].as_str().unwrap()))
+            .collect();
+        assert_eq!(statuses, [("alice", "exists"), ("bob", "registered"), ("carol", "registered")]);
+
+        let alice = state.user_directory.get_user("alice").await.unwrap();
+        assert_eq!(alice.addr, "127.0.0.1:9000");
+        assert!(state.user_directory.find_user_by_username("carol").await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn bulk_register_requires_admin_token() {
+        let settings = Settings {
+            admin_token: Some("admin-secret".to_string()),
+            ..Settings::default()
+        };
+        let state = test_state(true, settings);
+        let users = serde_json::json!([{ "username": "bob", "addr": "127.0.0.1:9002" }]);
+        let response = send(&state, post_json("/register/bulk", &users)).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert!(state.user_directory.find_user_by_username("bob").await.unwrap().is_none());
+    }
+
+    /// GET /whoami as if from `peer`, optionally with an X-Forwarded-For header
+    async fn whoami_from(state: &AppState, peer: &str, forwarded_for: Option<&str>) -> serde_json::Value {
+        let peer: SocketAddr = peer.parse().unwrap();
+        let mut request = Request::get("/whoami");
+        if let Some(forwarded_for) = forwarded_for {
+            request = request.header("X-Forwarded-For", forwarded_for);
+        }
+        let app = create_router(state.clone()).layer(axum::extract::connect_info::MockConnectInfo(peer));
+        let response = app.oneshot(request.body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        body_json(response).await
+    }
+
+    #[tokio::test]
+    async fn whoami_reports_peer_address() {
+        let state = test_state(true, Settings::default());
+        let body = whoami_from(&state, "203.0.113.7:51000", None).await;
+        assert_eq!(body["ip"], "203.0.113.7");
+        assert_eq!(body["forwarded"], false);
+
+        // X-Forwarded-For from an untrusted peer is ignored
+        let body = whoami_from(&state, "203.0.113.7:51000", Some("198.51.100.1")).await;
+        assert_eq!(body["ip"], "203.0.113.7");
+        assert_eq!(body["forwarded"], false);
+    }
+
+    #[tokio::test]
+    async fn whoami_believes_trusted_proxy() {
+        let settings = Settings {
+            trusted_proxies: vec!["10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap()],
+            ..Settings::default()
+        };
+        let state = test_state(true, settings);
+
+        let body = whoami_from(&state, "10.0.0.1:40000", Some("198.51.100.1, 10.0.0.2")).await;
+        assert_eq!(body["ip"], "198.51.100.1");
+        assert_eq!(body["forwarded"], true);
+
+        // a trusted proxy that forwards nothing is itself the client
+        let body = whoami_from(&state, "10.0.0.1:40000", None).await;
+        assert_eq!(body["ip"], "10.0.0.1");
+        assert_eq!(body["forwarded"], false);
+    }
+
+    /// A client P2P server whose /p2p/ping echoes the nonce, or answers a wrong one
+    async fn spawn_ping_server(echo_nonce: bool) -> SocketAddr {
+        let app = Router::new().route(
+            "/p2p/ping",
+            axum::routing::get(move |Query(query): Query<HashMap<String, String>>| async move {
+                let nonce = if echo_nonce { query["nonce"].clone() } else { "wrong".to_string() };
+                Json(serde_json::json!({ "nonce": nonce }))
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        addr
+    }
+
+    fn verifying_settings() -> Settings {
+        Settings {
+            verify_client_addr: true,
+            ..Settings::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn verified_address_is_registered() {
+        let state = test_state(true, verifying_settings());
+        let addr = spawn_ping_server(true).await;
+
+        let register = serde_json::json!({ "username": "alice", "addr": addr.to_string() });
+        let response = send(&state, post_json("/register", &register)).await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert!(state.user_directory.find_user_by_username("alice").await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn unverified_address_is_rejected() {
+        let state = test_state(true, verifying_settings());
+        let addr = spawn_ping_server(false).await;
+
+        let register = serde_json::json!({ "username": "alice", "addr": addr.to_string() });
+        let response = send(&state, post_json("/register", &register)).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let message = body_json(response).await["message"].as_str().unwrap().to_string();
+        assert!(message.contains("wrong nonce"), "{}", message);
+        assert!(state.user_directory.find_user_by_username("alice").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn retried_register_with_idempotency_key_is_replayed() {
+        let state = test_state(true, Settings::default());
+        let register = serde_json::json!({ "username": "alice", "addr": "127.0.0.1:9000" });
+        let attempt = || {
+            Request::post("/register")
+                .header(header::CONTENT_TYPE, "application/json")
+                .header(crate::middleware::IDEMPOTENCY_KEY_HEADER, "register-alice-1")
+                .body(Body::from(register.to_string()))
+                .unwrap()
+        };
+
+        let first = send(&state, attempt()).await;
+        assert_eq!(first.status(), StatusCode::CREATED);
+        let first_body = to_bytes(first.into_body(), usize::MAX).await.unwrap();
+
+        let retry = send(&state, attempt()).await;
+        assert_eq!(retry.status(), StatusCode::CREATED);
+        assert_eq!(retry.headers()["Idempotent-Replayed"], "true");
+        let retry_body = to_bytes(retry.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(retry_body, first_body);
+
+        let users = body_json(send(&state, get("/users")).await).await;
+        assert_eq!(users["users"].as_array().unwrap().len(), 1);
+
+        // without the key the duplicate is still a conflict
+        let response = send(&state, post_json("/register", &register)).await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+    }
+
+    fn register_with_idempotency_key(key: &str, register: &serde_json::Value) -> Request<Body> {
+        Request::post("/register")
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(crate::middleware::IDEMPOTENCY_KEY_HEADER, key)
+            .body(Body::from(register.to_string()))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn idempotency_key_reused_with_other_body_is_rejected() {
+        let state = test_state(true, Settings::default());
+        let alice = serde_json::json!({ "username": "alice", "addr": "127.0.0.1:9000" });
+        let bob = serde_json::json!({ "username": "bob", "addr": "127.0.0.1:9001" });
+
+        let first = send(&state, register_with_idempotency_key("key-1", &alice)).await;
+        assert_eq!(first.status(), StatusCode::CREATED);
+
+        let reused = send(&state, register_with_idempotency_key("key-1", &bob)).await;
+        assert_eq!(reused.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert!(state.user_directory.find_user_by_username("bob").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn abandoned_idempotency_key_is_released() {
+        let state = test_state(true, verifying_settings());
+        // Accepts the address check's connection but never answers it
+        let silent = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let register = serde_json::json!({
+            "username": "alice",
+            "addr": silent.local_addr().unwrap().to_string(),
+        });
+
+        // The client gives up while the handler is still waiting on the address check
+        let request = send(&state, register_with_idempotency_key("key-1", &register));
+        assert!(tokio::time::timeout(std::time::Duration::from_millis(200), request).await.is_err());
+
+        let mut released = false;
+        for _ in 0..50 {
+            if state.idempotency_keys.read().await.is_empty() {
+                released = true;
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert!(released, "cancelled request left its key in progress");
+    }
+
+    #[tokio::test]
+    async fn stale_in_progress_key_can_be_retried() {
+        let state = test_state(true, Settings::default());
+        let register = serde_json::json!({ "username": "alice", "addr": "127.0.0.1:9000" });
+        let body_hash = <sha2::Sha256 as sha2::Digest>::digest(register.to_string().as_bytes()).into();
+        state.idempotency_keys.write().await.insert(
+            "/register key-1".to_string(),
+            crate::middleware::IdempotencyEntry::InProgress {
+                body_hash,
+                started_at: Instant::now() - std::time::Duration::from_secs(120),
+            },
+        );
+
+        let retry = send(&state, register_with_idempotency_key("key-1", &register)).await;
+        assert_eq!(retry.status(), StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn user_profile_hides_key_hash() {
+        let state = test_state(true, Settings::default());
+        register_with_key(&state, "alice").await;
+
+        let response = send(&state, get("/users/alice")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = body_json(response).await;
+        assert!(!body.to_string().contains(KEY_HASH));
+        assert_eq!(body["username"], "alice");
+        assert_eq!(body["has_signing_key"], true);
+    }
+
+    #[tokio::test]
+    async fn user_list_hides_key_hash() {
+        let state = test_state(true, Settings::default());
+        register_with_key(&state, "alice").await;
+
+        let response = send(&state, get("/users")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = body_json(response).await;
+        assert!(!body.to_string().contains(KEY_HASH));
+        assert_eq!(body["users"][0]["has_signing_key"], true);
     }
 }
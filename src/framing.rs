@@ -0,0 +1,199 @@
+//! Length-delimited framing for the election TCP protocol
+//!
+//! Each message is a u32 big-endian byte length followed by that many bytes
+//! of JSON. Unlike newline-terminated JSON this survives payloads containing
+//! newlines and writes split across packets. All nodes must run the same
+//! framing, so upgrade the whole cluster together.
+//!
+//! When a cluster secret is configured, each frame instead carries the JSON
+//! message plus an HMAC-SHA256 tag over it, and frames with a bad tag are
+//! rejected before the message is acted on. The secret travels in a `Framing`
+//! value rather than process state, so each node (or test) picks its own.
+
+use anyhow::Context;
+use hmac::{Hmac, Mac};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::Sha256;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Authenticated frame body: the serialized message and its hex HMAC tag
+#[derive(Serialize, Deserialize)]
+struct SignedFrame {
+    body: String,
+    mac: String,
+}
+
+/// Upper bound on a single frame, so a bad length prefix can't make us allocate gigabytes
+pub const MAX_FRAME_BYTES: usize = 1024 * 1024;
+
+/// Serialize `msg` as JSON and write it as one frame
+pub async fn write_frame<W, T>(writer: &mut W, msg: &T) -> anyhow::Result<()>
+where
+    W: AsyncWrite + Unpin,
+    T: Serialize,
+{
+    let body = serde_json::to_vec(msg)?;
+    if body.len() > MAX_FRAME_BYTES {
+        anyhow::bail!("frame too large: {} bytes (max {})", body.len(), MAX_FRAME_BYTES);
+    }
+    writer.write_all(&(body.len() as u32).to_be_bytes()).await?;
+    writer.write_all(&body).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Read one frame and parse it as JSON.
+/// Returns `None` if the peer closed the connection before sending anything.
+pub async fn read_frame<R, T>(reader: &mut R) -> anyhow::Result<Option<T>>
+where
+    R: AsyncRead + Unpin,
+    T: DeserializeOwned,
+{
+    let mut len_buf = [0u8; 4];
+    match reader.read_exact(&mut len_buf).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+
+    let len = u32::from_be_bytes(len_buf) as usize;
+    if len > MAX_FRAME_BYTES {
+        anyhow::bail!("frame too large: {} bytes (max {})", len, MAX_FRAME_BYTES);
+    }
+
+    let mut body = vec![0u8; len];
+    reader.read_exact(&mut body).await.context("read frame body")?;
+    let msg = serde_json::from_slice(&body).context("parse frame json")?;
+    Ok(Some(msg))
+}
+
+/// How election messages are framed: plain, or HMAC-tagged with the cluster secret
+#[derive(Clone, Default)]
+pub struct Framing {
+    secret: Option<Arc<[u8]>>,
+}
+
+impl std::fmt::Debug for Framing {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Framing")
+            .field("hmac", &self.secret.is_some())
+            .finish()
+    }
+}
+
+fn message_mac(secret: &[u8], body: &[u8]) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(body);
+    mac
+}
+
+impl Framing {
+    /// Plain framing without a secret; with one, every message must carry its HMAC tag
+    pub fn new(cluster_secret: Option<&str>) -> anyhow::Result<Self> {
+        match cluster_secret {
+            Some("") => anyhow::bail!("cluster_secret must not be empty"),
+            Some(secret) => Ok(Self {
+                secret: Some(secret.as_bytes().into()),
+            }),
+            None => Ok(Self::default()),
+        }
+    }
+
+    /// Write an election message, tagging it with the cluster HMAC if one is configured
+    pub async fn write_message<W, T>(&self, writer: &mut W, msg: &T) -> anyhow::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+        T: Serialize,
+    {
+        match &self.secret {
+            Some(secret) => {
+                let body = serde_json::to_string(msg)?;
+                let mac = hex::encode(message_mac(secret, body.as_bytes()).finalize().into_bytes());
+                write_frame(writer, &SignedFrame { body, mac }).await
+            }
+            None => write_frame(writer, msg).await,
+        }
+    }
+
+    /// Read an election message, verifying its HMAC tag if a cluster secret is configured.
+    /// A message with a missing or wrong tag is an error and must not be acted on.
+    pub async fn read_message<R, T>(&self, reader: &mut R) -> anyhow::Result<Option<T>>
+    where
+        R: AsyncRead + Unpin,
+        T: DeserializeOwned,
+    {
+        let Some(secret) = &self.secret else {
+            return read_frame(reader).await;
+        };
+
+        let Some(frame) = read_frame::<_, SignedFrame>(reader).await? else {
+            return Ok(None);
+        };
+        let tag = hex::decode(&frame.mac).context("decode message HMAC")?;
+        message_mac(secret, frame.body.as_bytes())
+            .verify_slice(&tag)
+            .map_err(|_| anyhow::anyhow!("bad message HMAC, dropping message"))?;
+
+        let msg = serde_json::from_str(&frame.body).context("parse message json")?;
+        Ok(Some(msg))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Note {
+        text: String,
+    }
+
+    #[tokio::test]
+    async fn frame_round_trips_message_with_newlines() {
+        let sent = Note {
+            text: "first line\nsecond line\r\n\nlast".to_string(),
+        };
+        // A tiny buffer forces the frame through in several partial writes
+        let (mut writer, mut reader) = tokio::io::duplex(8);
+        let (written, received) = tokio::join!(write_frame(&mut writer, &sent), read_frame::<_, Note>(&mut reader));
+        written.unwrap();
+        assert_eq!(received.unwrap(), Some(sent));
+
+        drop(writer);
+        assert_eq!(read_frame::<_, Note>(&mut reader).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn oversized_length_prefix_is_rejected() {
+        let (mut writer, mut reader) = tokio::io::duplex(64);
+        writer
+            .write_all(&((MAX_FRAME_BYTES + 1) as u32).to_be_bytes())
+            .await
+            .unwrap();
+        assert!(read_frame::<_, Note>(&mut reader).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn signed_messages_need_the_same_secret() {
+        let signed = Framing::new(Some("cluster-secret")).unwrap();
+        let sent = Note { text: "hello".to_string() };
+
+        let (mut writer, mut reader) = tokio::io::duplex(1024);
+        signed.write_message(&mut writer, &sent).await.unwrap();
+        assert_eq!(signed.read_message::<_, Note>(&mut reader).await.unwrap(), Some(sent));
+
+        let other = Framing::new(Some("other-secret")).unwrap();
+        signed.write_message(&mut writer, &Note { text: "x".to_string() }).await.unwrap();
+        assert!(other.read_message::<_, Note>(&mut reader).await.is_err());
+
+        // Unsigned frames are refused where a secret is set
+        let (mut writer, mut reader) = tokio::io::duplex(1024);
+        Framing::default().write_message(&mut writer, &Note { text: "x".to_string() }).await.unwrap();
+        assert!(signed.read_message::<_, Note>(&mut reader).await.is_err());
+
+        assert!(Framing::new(Some("")).is_err());
+    }
+}
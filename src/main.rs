@@ -1,33 +1,101 @@
 //! Main entry point - Leader Election + User Registration
 
 mod registration;
+mod addr_check;
 mod api;
+mod framing;
+mod mdns;
+mod middleware;
+mod settings;
+mod signing;
+mod transport;
 
 use api::{AppState, create_router};
 use registration::{RegistrationConfig, UserDirectory};
+use settings::Settings;
 
 use anyhow::Context;
 use clap::Parser;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fs;
-use std::net::SocketAddr;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
 use std::sync::Arc;
 use std::time::Instant;
 use sysinfo::{CpuExt, System, SystemExt};
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::RwLock;
+use tokio::net::TcpListener;
+use tokio::sync::{watch, RwLock};
 use tokio::time::sleep;
 use chrono::Utc;
 use std::time::Duration as StdDuration;
 use chrono::Duration as ChronoDuration;
+use futures::future::join_all;
 use rand::Rng;
-use tracing::info;
+use tracing::{debug, info, warn};
+
+
+/// How the election timeout is drawn from [election_timeout_min_ms, election_timeout_max_ms]
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum ElectionJitter {
+    /// Uniform over the whole range every time
+    #[default]
+    Uniform,
+    /// Start with the lower quarter of the range and double the window after
+    /// each election that didn't settle a leader, so dueling candidates spread out
+    Backoff,
+}
+
+/// Upper bound of the timeout window after `failed_elections` unsettled elections in a row
+fn election_window_max(cfg: &Config, failed_elections: u32) -> u64 {
+    let (min, max) = (cfg.election_timeout_min_ms, cfg.election_timeout_max_ms);
+    match cfg.election_jitter {
+        ElectionJitter::Uniform => max,
+        ElectionJitter::Backoff => {
+            let span = max.saturating_sub(min);
+            let window = (span / 4).saturating_mul(1u64 << failed_elections.min(16));
+            min.saturating_add(window.max(1)).min(max)
+        }
+    }
+}
+
+fn random_election_timeout(cfg: &Config, failed_elections: u32) -> u64 {
+    let upper = election_window_max(cfg, failed_elections);
+    rand::thread_rng().gen_range(cfg.election_timeout_min_ms..=upper)
+}
+
+/// Gap between election retries while no leader is known: half the minimum
+/// timeout, doubling with each unsettled election up to the maximum timeout
+fn leaderless_retry_gap(cfg: &Config, failed_elections: u32) -> u64 {
+    (cfg.election_timeout_min_ms / 2)
+        .max(1)
+        .saturating_mul(1u64 << failed_elections.min(16))
+        .min(cfg.election_timeout_max_ms)
+}
 
+/// Average CPU usage across cores, clamped to [0, 100].
+/// Returns None when no readings are available yet (e.g. before the first refresh
+/// has populated cpus()), so callers keep the previous value instead of storing NaN.
+fn average_cpu_usage(readings: &[f32]) -> Option<f32> {
+    if readings.is_empty() {
+        return None;
+    }
+    let avg = readings.iter().sum::<f32>() / readings.len() as f32;
+    if avg.is_nan() {
+        return None;
+    }
+    Some(avg.clamp(0.0, 100.0))
+}
 
-fn random_election_timeout(cfg: &Config) -> u64 {
-    rand::thread_rng().gen_range(cfg.election_timeout_min_ms..=cfg.election_timeout_max_ms)
+/// Resolve a configured node address (IP literal or hostname) to a single SocketAddr
+fn resolve_node_addr(addr: &str) -> anyhow::Result<SocketAddr> {
+    if let Ok(parsed) = addr.parse() {
+        return Ok(parsed);
+    }
+    addr.to_socket_addrs()
+        .with_context(|| format!("invalid node address '{}'", addr))?
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("node address '{}' did not resolve", addr))
 }
 
 #[derive(Parser, Debug)]
@@ -35,21 +103,148 @@ struct Args {
     #[clap(long, default_value = "config.toml")]
     config: String,
 
+    /// Overrides bind_addr from the config
+    #[clap(long, alias = "this-node")]
+    bind_addr: Option<String>,
+
+    /// Server settings (bucket, credentials, API port, feature flags); env vars override it
+    #[clap(long, default_value = "app.toml")]
+    settings: String,
+
+    /// Keep users, images and notes in memory only (no Firebase calls); also TEST_MODE=true
     #[clap(long)]
-    this_node: Option<String>,
+    test_mode: bool,
 }
 
 #[derive(Deserialize, Debug, Clone)]
 struct Config {
-    this_node: String,
+    /// Address the election listener binds to; `this_node` is accepted as an alias
+    #[serde(alias = "this_node")]
+    bind_addr: String,
+    /// Address peers use to reach this node (also used to recognise ourselves in
+    /// peer lists and elections); defaults to bind_addr
+    #[serde(default)]
+    advertise_addr: Option<String>,
     peers: Vec<String>,
     heartbeat_interval_ms: u64,
     election_timeout_min_ms: u64,
     election_timeout_max_ms: u64,
+    /// "uniform" (default) or "backoff"
+    #[serde(default)]
+    election_jitter: ElectionJitter,
     leader_term_ms: u64,
     net_timeout_ms: u64,
     cpu_refresh_ms: u64,
     election_retry_ms: u64,
+    /// Serve the HTTP API on bind_addr's IP at this port (instead of 0.0.0.0:API_PORT)
+    #[serde(default)]
+    http_port: Option<u16>,
+    /// Mutual TLS for election traffic; plain TCP when absent
+    #[serde(default)]
+    tls: Option<transport::TlsConfig>,
+    /// Pre-shared secret used to HMAC every election message (same on all nodes)
+    #[serde(default)]
+    cluster_secret: Option<String>,
+    /// Election address -> HTTP base URL, so followers can redirect writes to the leader
+    #[serde(default)]
+    peer_http_urls: HashMap<String, String>,
+    /// Advertise this node on the LAN as a `_cloud-steg._tcp` mDNS service
+    #[serde(default)]
+    mdns_advertise: bool,
+    /// Nodes (including this one) that must answer before an election may pick a
+    /// leader; defaults to a majority of the cluster
+    #[serde(default)]
+    min_quorum: Option<usize>,
+    /// Node snapshot (term/state/leader) used to recover the term after a crash
+    #[serde(default = "default_snapshot_path")]
+    snapshot_path: String,
+}
+
+fn default_snapshot_path() -> String {
+    "data/node_snapshot.json".to_string()
+}
+
+/// Resolve the listen address and the advertised address (bind_addr unless
+/// advertise_addr is set); peers must be able to connect to the latter
+fn node_addrs(cfg: &Config) -> anyhow::Result<(SocketAddr, SocketAddr)> {
+    let bind_addr = resolve_node_addr(&cfg.bind_addr).context("resolve bind_addr")?;
+    let advertise_addr = match &cfg.advertise_addr {
+        Some(advertise) => resolve_node_addr(advertise).context("resolve advertise_addr")?,
+        None => bind_addr,
+    };
+    if advertise_addr.ip().is_unspecified() {
+        anyhow::bail!(
+            "advertised address {} is not routable; set advertise_addr when binding to a wildcard address",
+            advertise_addr
+        );
+    }
+    Ok((bind_addr, advertise_addr))
+}
+
+/// Resolve the configured peers, dropping duplicates and this node's own address
+fn resolve_peers(configured: &[String], this_addr: SocketAddr) -> anyhow::Result<Vec<SocketAddr>> {
+    let mut peers = Vec::with_capacity(configured.len());
+    for peer in configured {
+        let addr = resolve_node_addr(peer).with_context(|| format!("resolve peer {}", peer))?;
+        if addr == this_addr {
+            info!("Peer list includes this node ({}), excluding it", peer);
+        } else if !peers.contains(&addr) {
+            peers.push(addr);
+        }
+    }
+    Ok(peers)
+}
+
+/// Configured quorum, or a majority of this node plus its (deduplicated) peers
+fn min_quorum(cfg: &Config, peers: &[SocketAddr]) -> usize {
+    cfg.min_quorum.unwrap_or(peers.len().div_ceil(2) + 1)
+}
+
+/// Upper bound on the election timeout after repeated no-quorum elections, as a multiple of the max
+const NO_QUORUM_BACKOFF_FACTOR: u64 = 8;
+
+/// How long the storage probe may take before this node takes leadership
+const LEADER_STORAGE_PROBE_SECS: u64 = 3;
+
+/// A leader that can't reach storage fails every request, so a node checks
+/// the backend before taking the role; `Err` says why it can't lead
+async fn probe_storage(user_directory: &UserDirectory) -> Result<(), String> {
+    match tokio::time::timeout(
+        StdDuration::from_secs(LEADER_STORAGE_PROBE_SECS),
+        user_directory.check_storage(),
+    )
+    .await
+    {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err(format!("no answer within {}s", LEADER_STORAGE_PROBE_SECS)),
+    }
+}
+
+/// Lowest CPU wins; equal CPU goes to the lowest address
+fn pick_leader(collected: &HashMap<String, f32>) -> Option<(String, f32)> {
+    let mut chosen: Option<(String, f32)> = None;
+    for (addr, cpu_val) in collected.iter() {
+        match &chosen {
+            None => chosen = Some((addr.clone(), *cpu_val)),
+            Some((caddr, cval)) => {
+                if *cpu_val < *cval || (*cpu_val == *cval && addr < caddr) {
+                    chosen = Some((addr.clone(), *cpu_val));
+                }
+            }
+        }
+    }
+    chosen
+}
+
+#[derive(Debug, PartialEq)]
+enum ElectionOutcome {
+    /// A leader was chosen and announced
+    Decided,
+    /// Too few nodes answered; the term bump was undone
+    NoQuorum,
+    /// A leader for this term or a later one heartbeated us mid-election
+    Superseded,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -57,12 +252,29 @@ struct Config {
 enum Message {
     Heartbeat { leader: String, term_end_unix: u64, term: u64 },
     GetCpu { term: u64, initiator_addr: String, initiator_cpu: f32 },
-    CpuResp { cpu_percent: f32, addr: String, term: u64 },
+    CpuResp {
+        cpu_percent: f32,
+        addr: String,
+        term: u64,
+        /// Already voted for another initiator this term (absent from older nodes)
+        #[serde(default)]
+        vote_denied: bool,
+    },
     LeaderAnnounce { leader: String, term_end_unix: u64, term: u64 },
     Ping,
+    StatusReq,
+    StatusResp {
+        state: State,
+        term: u64,
+        leader: Option<String>,
+        /// Port the node serves its HTTP API on (absent from older nodes)
+        #[serde(default)]
+        http_port: Option<u16>,
+    },
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum State {
     Follower,
     Leader,
@@ -77,6 +289,321 @@ pub struct NodeState {
     startup_time: Instant,
     current_term: u64,
     cpu_snapshot: f32,
+    metrics: ElectionMetrics,
+    /// term -> initiator whose GetCpu we answered first; one vote per term
+    votes_cast: HashMap<u64, String>,
+    /// Set while this node is running an election (from the term bump until
+    /// the result is broadcast), so stale heartbeats can't cut it short
+    is_electing: bool,
+    /// The last pre-leadership storage probe failed; GetCpu answers f32::MAX
+    /// so other initiators don't keep picking this node
+    storage_probe_failed: bool,
+    /// When `leader` last became None (kept by the election loop)
+    leaderless_since: Option<Instant>,
+    /// Last StatusReq poll of every peer (filled in while leader)
+    cluster_status: Vec<PeerStatus>,
+    /// peer address -> round-trip times of the messages we sent it
+    peer_latency: HashMap<String, LatencyHistogram>,
+    /// Where term/state/leader are persisted for crash recovery
+    snapshot_path: String,
+    /// Port this node serves its HTTP API on, reported to peers in StatusResp
+    http_port: Option<u16>,
+    /// Election message framing, HMAC-tagged when a cluster secret is configured
+    framing: framing::Framing,
+    /// Latest snapshot queued for the writer task, so disk I/O never runs under the state lock
+    snapshot_tx: watch::Sender<Option<NodeSnapshot>>,
+    /// Latest snapshot the writer task has durably written
+    durable_tx: Arc<watch::Sender<Option<NodeSnapshot>>>,
+}
+
+/// Term, role and leader as persisted to disk
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NodeSnapshot {
+    pub term: u64,
+    pub state: State,
+    pub leader: Option<String>,
+}
+
+impl NodeState {
+    /// A fresh follower; the term comes from `restored` when there is a snapshot
+    fn new(snapshot_path: String, restored: Option<NodeSnapshot>) -> Self {
+        Self {
+            state: State::Follower,
+            leader: None,
+            last_heartbeat: None,
+            term_end: None,
+            startup_time: Instant::now(),
+            current_term: restored.as_ref().map_or(0, |snapshot| snapshot.term),
+            cpu_snapshot: 0.0,
+            metrics: ElectionMetrics::default(),
+            votes_cast: HashMap::new(),
+            is_electing: false,
+            storage_probe_failed: false,
+            leaderless_since: None,
+            cluster_status: Vec::new(),
+            peer_latency: HashMap::new(),
+            snapshot_path,
+            http_port: None,
+            framing: framing::Framing::default(),
+            snapshot_tx: watch::channel(restored.clone()).0,
+            durable_tx: Arc::new(watch::channel(restored).0),
+        }
+    }
+
+    /// Atomically and durably write a snapshot: write and fsync a temp file next
+    /// to `path`, rename it over `path`, then fsync the directory
+    fn save_snapshot(path: &str, snapshot: &NodeSnapshot) -> anyhow::Result<()> {
+        use std::io::Write;
+        let path = std::path::Path::new(path);
+        let dir = path.parent().filter(|d| !d.as_os_str().is_empty());
+        if let Some(dir) = dir {
+            fs::create_dir_all(dir).context("create snapshot directory")?;
+        }
+        let tmp = path.with_extension("json.tmp");
+        {
+            let mut file = fs::File::create(&tmp).context("create snapshot temp file")?;
+            file.write_all(&serde_json::to_vec_pretty(snapshot)?).context("write snapshot")?;
+            file.sync_all().context("fsync snapshot")?;
+        }
+        fs::rename(&tmp, path).context("rename snapshot into place")?;
+        // The rename itself only survives a crash once the directory entry is on disk
+        #[cfg(unix)]
+        fs::File::open(dir.unwrap_or_else(|| std::path::Path::new(".")))
+            .and_then(|d| d.sync_all())
+            .context("fsync snapshot directory")?;
+        Ok(())
+    }
+
+    /// Read the snapshot at `path`; None if there is none or it can't be parsed
+    fn load_snapshot(path: &str) -> Option<NodeSnapshot> {
+        let data = fs::read(path).ok()?;
+        match serde_json::from_slice(&data) {
+            Ok(snapshot) => Some(snapshot),
+            Err(e) => {
+                warn!("Ignoring unreadable node snapshot {}: {}", path, e);
+                None
+            }
+        }
+    }
+
+    fn current_snapshot(&self) -> NodeSnapshot {
+        NodeSnapshot {
+            term: self.current_term,
+            state: self.state.clone(),
+            leader: self.leader.clone(),
+        }
+    }
+
+    /// Queue term/state/leader for the snapshot writer if they changed since the
+    /// last queued snapshot. Call after every term increment or state transition.
+    fn persist_snapshot(&mut self) {
+        let current = self.current_snapshot();
+        self.snapshot_tx.send_if_modified(|queued| {
+            if queued.as_ref() == Some(&current) {
+                return false;
+            }
+            *queued = Some(current);
+            true
+        });
+    }
+
+    /// Snapshots queued by `persist_snapshot`, and where to report the ones
+    /// written, for `run_snapshot_writer`
+    fn snapshot_channels(
+        &self,
+    ) -> (watch::Receiver<Option<NodeSnapshot>>, Arc<watch::Sender<Option<NodeSnapshot>>>) {
+        (self.snapshot_tx.subscribe(), self.durable_tx.clone())
+    }
+
+    /// Follows the latest durably written snapshot
+    fn durable_snapshots(&self) -> watch::Receiver<Option<NodeSnapshot>> {
+        self.durable_tx.subscribe()
+    }
+}
+
+/// How long the snapshot writer waits before retrying a failed write
+const SNAPSHOT_RETRY_MS: u64 = 500;
+
+/// Write queued snapshots to `path` on the blocking pool and publish each one
+/// to `durable` once it is on disk. Only the newest pending snapshot is written
+/// when several are queued while a write is in flight; a failed write is retried
+/// until it succeeds or a newer snapshot replaces it.
+async fn run_snapshot_writer(
+    path: String,
+    mut updates: watch::Receiver<Option<NodeSnapshot>>,
+    durable: Arc<watch::Sender<Option<NodeSnapshot>>>,
+) {
+    while updates.changed().await.is_ok() {
+        loop {
+            let Some(snapshot) = updates.borrow_and_update().clone() else { break };
+            let target = path.clone();
+            let written = snapshot.clone();
+            let error = match tokio::task::spawn_blocking(move || NodeState::save_snapshot(&target, &written)).await {
+                Ok(Ok(())) => {
+                    durable.send_replace(Some(snapshot));
+                    break;
+                }
+                Ok(Err(e)) => e,
+                Err(e) => anyhow::anyhow!("snapshot write panicked: {}", e),
+            };
+            warn!("Failed to save node snapshot to {}, retrying: {}", path, error);
+            tokio::select! {
+                _ = sleep(StdDuration::from_millis(SNAPSHOT_RETRY_MS)) => {}
+                changed = updates.changed() => {
+                    if changed.is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Wait until a snapshot with at least `term` is on disk, so a term is never
+/// used before a crash can no longer make this node forget it
+async fn wait_term_durable(
+    durable: &mut watch::Receiver<Option<NodeSnapshot>>,
+    term: u64,
+    timeout_ms: u64,
+) -> anyhow::Result<()> {
+    let written = durable.wait_for(|snapshot| snapshot.as_ref().is_some_and(|s| s.term >= term));
+    match tokio::time::timeout(StdDuration::from_millis(timeout_ms), written).await {
+        Ok(Ok(_)) => Ok(()),
+        Ok(Err(_)) => anyhow::bail!("snapshot writer stopped before term {} was saved", term),
+        Err(_) => anyhow::bail!("term {} not saved within {} ms", term, timeout_ms),
+    }
+}
+
+/// Write the current snapshot even if it looks unchanged (on shutdown, so a failed
+/// earlier write is retried); the state lock is only held to copy the snapshot
+async fn flush_snapshot(shared: &Arc<RwLock<NodeState>>) -> anyhow::Result<()> {
+    let (path, snapshot, durable) = {
+        let ns = shared.read().await;
+        (ns.snapshot_path.clone(), ns.current_snapshot(), ns.durable_tx.clone())
+    };
+    let written = snapshot.clone();
+    tokio::task::spawn_blocking(move || NodeState::save_snapshot(&path, &written))
+        .await
+        .context("snapshot write task")??;
+    durable.send_replace(Some(snapshot));
+    Ok(())
+}
+
+/// Resolves on Ctrl+C or, on unix, SIGTERM
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            warn!("Failed to listen for Ctrl+C: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(e) => {
+                warn!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
+
+/// A peer's role and term as last reported to the leader
+#[derive(Debug, Clone, Serialize)]
+pub struct PeerStatus {
+    pub addr: String,
+    pub state: Option<State>,
+    pub term: Option<u64>,
+    pub leader: Option<String>,
+    pub http_port: Option<u16>,
+    pub reachable: bool,
+}
+
+/// Keep vote records for this many terms behind the current one
+const VOTE_HISTORY_TERMS: u64 = 10;
+
+/// Upper bounds (inclusive, ms) of all but the last latency bucket; the last is everything slower
+pub const LATENCY_BUCKET_BOUNDS_MS: [u64; 9] = [1, 5, 10, 25, 50, 100, 250, 500, 1000];
+
+/// Round-trip times of election TCP messages to one peer
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct LatencyHistogram {
+    pub buckets: [u64; 10],
+    pub total_samples: u64,
+}
+
+impl LatencyHistogram {
+    fn record(&mut self, elapsed: StdDuration) {
+        let ms = elapsed.as_millis() as u64;
+        let bucket = LATENCY_BUCKET_BOUNDS_MS
+            .iter()
+            .position(|bound| ms <= *bound)
+            .unwrap_or(LATENCY_BUCKET_BOUNDS_MS.len());
+        self.buckets[bucket] += 1;
+        self.total_samples += 1;
+    }
+}
+
+/// Record a completed request/response round trip to `peer`
+async fn record_latency(shared: &Arc<RwLock<NodeState>>, peer: &SocketAddr, started: Instant) {
+    shared
+        .write()
+        .await
+        .peer_latency
+        .entry(peer.to_string())
+        .or_default()
+        .record(started.elapsed());
+}
+
+/// Election counters since process start (never reset across terms)
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct ElectionMetrics {
+    pub elections_initiated: u64,
+    pub elections_won: u64,
+    pub elections_lost: u64,
+    /// Elections where the lowest CPU was tied and the address tiebreak decided
+    pub cpu_tiebreaks_resolved: u64,
+    pub heartbeats_sent: u64,
+    pub heartbeats_received: u64,
+    /// Times this node stepped down from leader
+    pub leader_steps_taken: u64,
+    /// Elections started early because no leader was known past the timeout
+    pub leaderless_timeouts: u64,
+}
+
+impl ElectionMetrics {
+    /// Render as Prometheus text exposition format
+    pub fn to_prometheus(&self) -> String {
+        let counters = [
+            ("elections_initiated", "Elections started by this node", self.elections_initiated),
+            ("elections_won", "Elections this node won", self.elections_won),
+            ("elections_lost", "Elections this node ran and lost", self.elections_lost),
+            ("cpu_tiebreaks_resolved", "Elections decided by the address tiebreak", self.cpu_tiebreaks_resolved),
+            ("heartbeats_sent", "Leader heartbeats delivered to peers", self.heartbeats_sent),
+            ("heartbeats_received", "Leader heartbeats accepted", self.heartbeats_received),
+            ("leader_steps_taken", "Times this node stepped down from leader", self.leader_steps_taken),
+            ("leaderless_timeouts", "Elections retried early because no leader was known", self.leaderless_timeouts),
+        ];
+
+        let mut out = String::new();
+        for (name, help, value) in counters {
+            out.push_str(&format!("# HELP cloud_steg_election_{}_total {}\n", name, help));
+            out.push_str(&format!("# TYPE cloud_steg_election_{}_total counter\n", name));
+            out.push_str(&format!("cloud_steg_election_{}_total {}\n", name, value));
+        }
+        out
+    }
 }
 
 #[tokio::main]
@@ -95,44 +622,88 @@ async fn main() -> anyhow::Result<()> {
     let cfg_text = fs::read_to_string(&args.config).context("read config")?;
     let mut cfg: Config = toml::from_str(&cfg_text).context("parse config")?;
 
-    // Override this_node if provided
-    if let Some(node) = args.this_node {
-        cfg.this_node = node;
+    // Override bind_addr if provided
+    if let Some(bind) = args.bind_addr {
+        cfg.bind_addr = bind;
+    }
+
+    // Listen address may differ from the advertised one (NAT, 0.0.0.0)
+    let (bind_addr, this_addr) = node_addrs(&cfg)?;
+    // Canonical form, so every "is this me?" comparison against peers and leader
+    // addresses sees the same spelling regardless of how the config wrote it
+    let this_node = this_addr.to_string();
+
+    let peers = resolve_peers(&cfg.peers, this_addr)?;
+
+    let quorum = min_quorum(&cfg, &peers);
+    if quorum == 0 || quorum > peers.len() + 1 {
+        anyhow::bail!(
+            "min_quorum must be between 1 and the cluster size ({}), got {}",
+            peers.len() + 1,
+            quorum
+        );
     }
 
-    let this_addr: SocketAddr = cfg.this_node.parse().context("parse this_node as SocketAddr")?;
+    if let Some(tls_cfg) = &cfg.tls {
+        let tls = transport::ElectionTls::from_config(tls_cfg).context("load election TLS config")?;
+        transport::enable_tls(tls)?;
+    }
+    let framing = framing::Framing::new(cfg.cluster_secret.as_deref()).context("cluster_secret")?;
+
+    // Key by canonical election address, the form ns.leader uses
+    let mut peer_http_urls = HashMap::new();
+    for (node, url) in &cfg.peer_http_urls {
+        let addr = resolve_node_addr(node).with_context(|| format!("resolve peer_http_urls key {}", node))?;
+        let url = if url.contains("://") { url.clone() } else { format!("http://{}", url) };
+        peer_http_urls.insert(addr.to_string(), url);
+    }
 
     info!("Node Configuration:");
     info!("  Address: {}", this_addr);
-    info!("  Peers: {:?}", cfg.peers);
+    if bind_addr != this_addr {
+        info!("  Bind address: {}", bind_addr);
+    }
+    info!("  Peers: {:?}", peers);
+    info!("  Election quorum: {}/{}", quorum, peers.len() + 1);
+    info!("  Election TLS: {}", if cfg.tls.is_some() { "enabled" } else { "disabled" });
+    info!("  Message HMAC: {}", if cfg.cluster_secret.is_some() { "enabled" } else { "disabled" });
     info!("");
 
     // ========================================
     // INITIALIZE USER REGISTRATION SYSTEM
     // ========================================
     info!("Initializing user registration system...");
-    
-    let bucket_name = std::env::var("FIREBASE_BUCKET")
-        .expect("FIREBASE_BUCKET environment variable must be set (e.g., your-project.appspot.com)");
-    
-    let credentials_path = std::env::var("GOOGLE_APPLICATION_CREDENTIALS")
-        .unwrap_or_else(|_| "credentials/firebase-storage.json".to_string());
-    
-    let reg_config = RegistrationConfig::new(
-        &credentials_path,
-        bucket_name,
-        "registered-users",  // Folder prefix in Firebase Storage
-    );
-
-    let user_directory = match UserDirectory::new(reg_config).await {
-        Ok(dir) => {
-            info!("✓ User registration system initialized (Firebase Storage)");
-            Arc::new(dir)
-        }
-        Err(e) => {
-            eprintln!("⚠ Failed to initialize user registration: {}", e);
-            eprintln!("⚠ Continuing with leader election only...");
-            return Err(e.into());
+
+    let settings = Arc::new(Settings::load(&args.settings).context("load settings")?);
+    let test_mode = args.test_mode || settings.test_mode;
+
+    let user_directory = if test_mode {
+        info!("✓ User registration system initialized (in-memory, TEST MODE - nothing is persisted)");
+        Arc::new(UserDirectory::new_in_memory(
+            RegistrationConfig::default().with_quota(settings.quota()),
+        ))
+    } else {
+        let bucket_name = settings.firebase_bucket.clone().context(
+            "FIREBASE_BUCKET must be set in the environment or app.toml (e.g., your-project.appspot.com)",
+        )?;
+
+        let reg_config = RegistrationConfig::new(
+            &settings.credentials_path,
+            bucket_name,
+            "registered-users",  // Folder prefix in Firebase Storage
+        )
+        .with_quota(settings.quota());
+
+        match UserDirectory::new(reg_config).await {
+            Ok(dir) => {
+                info!("✓ User registration system initialized (Firebase Storage)");
+                Arc::new(dir)
+            }
+            Err(e) => {
+                eprintln!("⚠ Failed to initialize user registration: {}", e);
+                eprintln!("⚠ Continuing with leader election only...");
+                return Err(e.into());
+            }
         }
     };
 
@@ -141,28 +712,44 @@ async fn main() -> anyhow::Result<()> {
     // START HTTP API SERVER
     // ========================================
 
-    let peers: Vec<SocketAddr> = cfg
-        .peers
-        .iter()
-        .map(|s| s.parse().expect("invalid peer addr in config"))
-        .collect();
+    // Resume from the last persisted term so a crash mid-election can't reuse a term.
+    // Always restart as follower; leadership has to be re-established.
+    let restored = NodeState::load_snapshot(&cfg.snapshot_path);
+    if let Some(snapshot) = &restored {
+        info!(
+            "Restored node snapshot: term {} (was {:?}, leader {:?})",
+            snapshot.term, snapshot.state, snapshot.leader
+        );
+    }
+
+    let api_addr = match cfg.http_port {
+        Some(http_port) => SocketAddr::new(bind_addr.ip(), http_port),
+        // All interfaces, in the same address family as the election listener
+        None if bind_addr.is_ipv6() => SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), settings.api_port),
+        None => SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), settings.api_port),
+    };
+    let http_port = api_addr.port();
 
     let shared = Arc::new(RwLock::new(NodeState {
-        state: State::Follower,
-        leader: None,
-        last_heartbeat: None,
-        term_end: None,
-        startup_time: Instant::now(),
-        current_term: 0,
-        cpu_snapshot: 0.0,
+        http_port: Some(http_port),
+        framing,
+        ..NodeState::new(cfg.snapshot_path.clone(), restored)
     }));
-    
-    let api_port = std::env::var("API_PORT")
-        .unwrap_or_else(|_| "3000".to_string())
-        .parse::<u16>()
-        .unwrap_or(3000);
-    
-    let api_addr = format!("0.0.0.0:{}", api_port);
+    let (snapshot_updates, durable_snapshots) = shared.read().await.snapshot_channels();
+    tokio::spawn(run_snapshot_writer(cfg.snapshot_path.clone(), snapshot_updates, durable_snapshots));
+
+    // Held for the life of the process; dropping the daemon stops answering queries
+    let _mdns_daemon = if cfg.mdns_advertise {
+        match mdns::advertise(this_addr, http_port) {
+            Ok(daemon) => Some(daemon),
+            Err(e) => {
+                warn!("mDNS advertising disabled: {:#}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
     
     // Create online clients tracker
     let online_clients = Arc::new(RwLock::new(HashMap::new()));
@@ -171,28 +758,62 @@ async fn main() -> anyhow::Result<()> {
         user_directory: user_directory.clone(),
         node_state: shared.clone(),
         online_clients: online_clients.clone(),
+        http_port,
+        settings: settings.clone(),
+        election_timeout_max_ms: cfg.election_timeout_max_ms,
+        peer_http_urls: Arc::new(peer_http_urls),
+        idempotency_keys: Arc::new(RwLock::new(HashMap::new())),
+        metadata_cache: Arc::new(RwLock::new(HashMap::new())),
     };
     let app = create_router(app_state);
     
-    let api_addr_clone = api_addr.clone();
-    tokio::spawn(async move {
+    // Flipped to true on shutdown; the HTTP server stops accepting and drains in-flight requests
+    let (http_shutdown_tx, mut http_shutdown_rx) = watch::channel(false);
+    let api_addr_clone = api_addr;
+    let http_task = tokio::spawn(async move {
         match tokio::net::TcpListener::bind(&api_addr_clone).await {
             Ok(listener) => {
                 info!("🚀 HTTP API server listening on http://{}", api_addr_clone);
                 info!("   Endpoints:");
                 info!("     GET  /                        - Health check");
+                info!("     GET  /healthz/live            - Liveness probe");
+                info!("     GET  /healthz/ready           - Readiness probe (leader/recent leader + storage)");
+                info!("     GET  /election/metrics        - Election counters (JSON)");
+                info!("     GET  /election/latency_histogram - Election message round-trip times per peer");
+                info!("     GET  /metrics                 - Election counters (Prometheus)");
+                info!("     GET  /cluster                 - Role and term of every node (leader)");
                 info!("     POST /register                - Register new user");
                 info!("     POST /heartbeat               - Send heartbeat");
+                info!("     POST /heartbeat/batch         - Heartbeats for up to 100 clients at once");
                 info!("     GET  /users                   - List all registered users");
+                info!("     GET  /users/:username         - User profile with online status");
+                info!("     GET  /whoami                  - Caller's address as seen by the leader");
                 info!("     GET  /discover                - List online clients");
                 info!("     GET  /discover_with_images    - List online clients with images");
+                info!("     GET  /discover_stream         - Online clients with images as server-sent events");
                 info!("     POST /upload_image/:username  - Upload image (max 128x128)");
                 info!("     GET  /images/:username        - List user's images");
+                info!("     GET  /users/:username/quota   - Image usage and limits");
+                info!("     GET  /users/:username/images  - List user's images");
+                info!("     GET  /users/:username/images/:file[/thumbnail] - Image bytes with Content-Type");
                 info!("     GET  /image/:username/:file   - Download specific image");
+                info!("     POST /image_token             - Issue short-lived image download token");
+                info!("     GET  /user/:username/sample/:i - Download i-th sample image (raw bytes)");
+                info!("     PUT  /users/:username/sample-images - Add/replace sample images");
                 info!("     POST /add_note                - Add note to image");           // NEW
                 info!("     GET  /get_note/:username      - Get all notes for user");      // NEW
+                info!("     POST /admin/rename-user       - Rename user and move their files (X-Admin-Token)");
+                info!("     POST /register/bulk           - Import many users at once (X-Admin-Token)");
                 info!("");
-                if let Err(e) = axum::serve(listener, app).await {
+                // Connect info gives /whoami the caller's address
+                let service = app.into_make_service_with_connect_info::<SocketAddr>();
+                let graceful = async move {
+                    let _ = http_shutdown_rx.changed().await;
+                };
+                if let Err(e) = axum::serve(listener, service)
+                    .with_graceful_shutdown(graceful)
+                    .await
+                {
                     eprintln!("HTTP API server error: {}", e);
                 }
             }
@@ -208,9 +829,9 @@ async fn main() -> anyhow::Result<()> {
     // ========================================
     let online_clients_cleanup = online_clients.clone();
     let shared_cleanup = shared.clone();
+    let heartbeat_ttl_secs = settings.heartbeat_ttl_secs;
     tokio::spawn(async move {
         const CLEANUP_INTERVAL_SECS: u64 = 10;
-        const HEARTBEAT_TIMEOUT_SECS: u64 = 30;
         
         loop {
             sleep(StdDuration::from_secs(CLEANUP_INTERVAL_SECS)).await;
@@ -225,10 +846,10 @@ async fn main() -> anyhow::Result<()> {
                 let mut online = online_clients_cleanup.write().await;
                 let before_count = online.len();
                 
-                // Remove clients that haven't sent heartbeat in 30 seconds
+                // Remove clients that haven't sent a heartbeat within the TTL
                 online.retain(|username, client| {
                     let elapsed = client.last_heartbeat.elapsed().as_secs();
-                    if elapsed > HEARTBEAT_TIMEOUT_SECS {
+                    if !client.is_fresh(heartbeat_ttl_secs) {
                         info!("Removing stale client: {} (no heartbeat for {}s)", username, elapsed);
                         false
                     } else {
@@ -244,6 +865,27 @@ async fn main() -> anyhow::Result<()> {
         }
     });
 
+    // ========================================
+    // CLUSTER STATUS POLL (leader only)
+    // ========================================
+    let shared_status = shared.clone();
+    let peers_status = peers.clone();
+    let status_timeout_ms = cfg.net_timeout_ms;
+    tokio::spawn(async move {
+        const CLUSTER_POLL_INTERVAL_SECS: u64 = 5;
+
+        loop {
+            sleep(StdDuration::from_secs(CLUSTER_POLL_INTERVAL_SECS)).await;
+
+            let is_leader = shared_status.read().await.state == State::Leader;
+            if !is_leader {
+                continue;
+            }
+
+            poll_cluster_status(&peers_status, status_timeout_ms, &shared_status).await;
+        }
+    });
+
     // Give the HTTP server a moment to start
     sleep(StdDuration::from_millis(100)).await;
 
@@ -260,23 +902,25 @@ async fn main() -> anyhow::Result<()> {
         let mut sys = System::new_all();
         loop {
             sys.refresh_cpu();
-            let avg = sys.cpus().iter().map(|c| c.cpu_usage()).sum::<f32>()
-                / (sys.cpus().len() as f32);
-            {
+            let readings: Vec<f32> = sys.cpus().iter().map(|c| c.cpu_usage()).collect();
+            if let Some(avg) = average_cpu_usage(&readings) {
                 let mut w = cpu_clone.write().await;
                 *w = avg;
+            } else {
+                debug!("No CPU readings yet, keeping previous value");
             }
             sleep(StdDuration::from_millis(cpu_refresh)).await;
         }
     });
 
-    let listener = TcpListener::bind(this_addr).await?;
-    info!("✓ Leader election TCP listener bound to {}", this_addr);
+    let listener = TcpListener::bind(bind_addr).await?;
+    info!("✓ Leader election TCP listener bound to {}", bind_addr);
     info!("");
 
     let listener_shared = shared.clone();
     let cpu_for_handler = cpu.clone();
-    let this_node_str = cfg.this_node.clone();
+    let this_node_str = this_node.clone();
+    let listener_directory = user_directory.clone();
     tokio::spawn(async move {
         loop {
             match listener.accept().await {
@@ -284,8 +928,16 @@ async fn main() -> anyhow::Result<()> {
                     let s = listener_shared.clone();
                     let c = cpu_for_handler.clone();
                     let this_node = this_node_str.clone();
+                    let d = listener_directory.clone();
                     tokio::spawn(async move {
-                        if let Err(e) = handle_connection(stream, s, c, this_node).await {
+                        let stream = match transport::accept(stream).await {
+                            Ok(stream) => stream,
+                            Err(e) => {
+                                eprintln!("rejected connection from {}: {}", addr, e);
+                                return;
+                            }
+                        };
+                        if let Err(e) = handle_connection(stream, addr, s, c, this_node, d).await {
                             eprintln!("handler error from {}: {}", addr, e);
                         }
                     });
@@ -300,15 +952,38 @@ async fn main() -> anyhow::Result<()> {
     let shared_clone = shared.clone();
     let peers_clone = peers.clone();
     let cfg_clone = cfg.clone();
-    let this_addr_str = cfg.this_node.clone();
+    let this_addr_str = this_node.clone();
+    let election_directory = user_directory.clone();
     tokio::spawn(async move {
-        let mut election_timeout = random_election_timeout(&cfg_clone);
+        let mut election_timeout = random_election_timeout(&cfg_clone, 0);
+        // Elections in a row that didn't end with a leader heartbeating us (backoff jitter)
+        let mut failed_elections: u32 = 0;
+        let mut heartbeats_at_last_election: Option<u64> = None;
+        // When the last election was aborted for lack of quorum; the next one waits a full timeout from here
+        let mut no_quorum_at: Option<Instant> = None;
         
+        // When this node last started an election (paces leaderless retries)
+        let mut last_election_at: Option<Instant> = None;
+
         loop {
+            {
+                let mut ns = shared_clone.write().await;
+                match (&ns.leader, ns.leaderless_since) {
+                    (None, None) => ns.leaderless_since = Some(Instant::now()),
+                    (Some(_), Some(_)) => ns.leaderless_since = None,
+                    _ => {}
+                }
+            }
             {
                 let ns = shared_clone.read().await;
                 if ns.state == State::Follower {
-                    let should_elect = if let Some(last) = ns.last_heartbeat {
+                    // A heartbeat since our last election means that term settled
+                    if heartbeats_at_last_election.is_some_and(|seen| ns.metrics.heartbeats_received > seen) {
+                        failed_elections = 0;
+                        heartbeats_at_last_election = None;
+                    }
+
+                    let timed_out = if let Some(last) = ns.last_heartbeat {
                         println!("Last heartbeat received, elapsed: {} ms, current term: {}, timeout: {} ms", 
                                 last.elapsed().as_millis(), ns.current_term, election_timeout);
                         last.elapsed().as_millis() as u64 >= election_timeout
@@ -317,19 +992,75 @@ async fn main() -> anyhow::Result<()> {
                                 ns.startup_time.elapsed().as_millis(), ns.current_term, election_timeout);
                         ns.startup_time.elapsed().as_millis() as u64 >= (election_timeout)
                     };
+                    // No leader at all past the timeout (term expired with no successor, or the
+                    // chosen node declined): retry sooner than the full random timeout, backing
+                    // off as retries keep failing. A cluster that never elected is just starting up.
+                    let leaderless = ns.current_term > 0
+                        && ns.leader.is_none()
+                        && ns.last_heartbeat.is_none_or(|t| t.elapsed().as_millis() as u64 > election_timeout);
+                    let retry_gap = if leaderless {
+                        leaderless_retry_gap(&cfg_clone, failed_elections)
+                    } else {
+                        election_timeout
+                    };
+                    let due = if leaderless {
+                        last_election_at.is_none_or(|at| at.elapsed().as_millis() as u64 >= retry_gap)
+                    } else {
+                        timed_out
+                    };
+                    // After a no-quorum election the backed-off timeout applies even when leaderless
+                    let should_elect = due
+                        && no_quorum_at.is_none_or(|at| at.elapsed().as_millis() as u64 >= election_timeout);
                     
                     if should_elect {
+                        last_election_at = Some(Instant::now());
+                        if leaderless {
+                            println!("No leader known, retrying election after {} ms", retry_gap);
+                        }
+                        if heartbeats_at_last_election.is_some() {
+                            failed_elections = failed_elections.saturating_add(1);
+                        }
+                        heartbeats_at_last_election = Some(ns.metrics.heartbeats_received);
                         drop(ns);
-                        if let Err(e) =
-                            run_election(&peers_clone, &this_addr_str, &cfg_clone, shared_clone.clone(), cpu.clone()).await
-                        {
-                            eprintln!("election failed: {}", e);
+                        if leaderless {
+                            shared_clone.write().await.metrics.leaderless_timeouts += 1;
+                        }
+                        let outcome =
+                            run_election(
+                                &peers_clone,
+                                &this_addr_str,
+                                &cfg_clone,
+                                shared_clone.clone(),
+                                cpu.clone(),
+                                election_directory.clone(),
+                            )
+                            .await;
+                        match outcome {
+                            Ok(ElectionOutcome::NoQuorum) => {
+                                // Peers are unreachable; retrying at the usual rate only burns terms
+                                let cap = cfg_clone.election_timeout_max_ms * NO_QUORUM_BACKOFF_FACTOR;
+                                election_timeout = (election_timeout * 2).min(cap);
+                                no_quorum_at = Some(Instant::now());
+                                println!("No quorum, backing off election timeout to {} ms", election_timeout);
+                            }
+                            result => {
+                                no_quorum_at = None;
+                                if let Err(e) = result {
+                                    eprintln!("election failed: {}", e);
+                                }
+                                election_timeout = random_election_timeout(&cfg_clone, failed_elections);
+                                println!(
+                                    "New random election timeout: {} ms ({} unsettled elections in a row)",
+                                    election_timeout, failed_elections
+                                );
+                            }
                         }
-                        election_timeout = random_election_timeout(&cfg_clone);
-                        println!("New random election timeout: {} ms", election_timeout);
                     }
                 } else if ns.state == State::Leader {
-                    election_timeout = random_election_timeout(&cfg_clone);
+                    no_quorum_at = None;
+                    failed_elections = 0;
+                    heartbeats_at_last_election = None;
+                    election_timeout = random_election_timeout(&cfg_clone, 0);
                 }
             }
             sleep(StdDuration::from_millis(500)).await;
@@ -339,7 +1070,7 @@ async fn main() -> anyhow::Result<()> {
     let shared_clone2 = shared.clone();
     let peers_clone2 = peers.clone();
     let cfg_clone2 = cfg.clone();
-    let this_addr_str2 = cfg.this_node.clone();
+    let this_addr_str2 = this_node.clone();
     tokio::spawn(async move {
         loop {
             let is_leader = {
@@ -362,9 +1093,11 @@ async fn main() -> anyhow::Result<()> {
                     {
                         let mut ns = shared_clone2.write().await;
                         ns.state = State::Follower;
+                        ns.metrics.leader_steps_taken += 1;
                         ns.leader = None;
                         ns.term_end = None;
                         ns.last_heartbeat = None;
+                        ns.persist_snapshot();
                     }
                     sleep(StdDuration::from_millis(200)).await;
                 }
@@ -378,26 +1111,38 @@ async fn main() -> anyhow::Result<()> {
     info!("Use Ctrl+C to shutdown");
     info!("===========================================\n");
 
-    loop {
-        sleep(StdDuration::from_secs(60)).await;
+    shutdown_signal().await;
+    info!("Shutdown requested, finishing in-flight HTTP requests...");
+
+    // Registrations and uploads in flight still reach storage before we exit
+    let _ = http_shutdown_tx.send(true);
+    if let Err(e) = http_task.await {
+        warn!("HTTP server task ended abnormally: {}", e);
     }
+
+    // Everything else in memory (online clients, vote records) is rebuilt from
+    // heartbeats after a restart; the term is what has to survive
+    match flush_snapshot(&shared).await {
+        Ok(()) => info!("Node snapshot flushed to {}", cfg.snapshot_path),
+        Err(e) => warn!("Failed to flush node snapshot to {}: {}", cfg.snapshot_path, e),
+    }
+
+    info!("Shutdown complete");
+    Ok(())
 }
 
 async fn handle_connection(
-    mut stream: TcpStream,
+    mut stream: transport::BoxedStream,
+    peer: SocketAddr,
     shared: Arc<RwLock<NodeState>>,
     cpu: Arc<RwLock<f32>>,
     this_node: String,
+    user_directory: Arc<UserDirectory>,
 ) -> anyhow::Result<()> {
-    let peer = stream.peer_addr()?;
-    let (r, mut w) = stream.split();
-    let mut reader = BufReader::new(r);
-    let mut buf = String::new();
-    let n = reader.read_line(&mut buf).await?;
-    if n == 0 {
+    let framing = shared.read().await.framing.clone();
+    let Some(msg) = framing.read_message::<_, Message>(&mut stream).await? else {
         return Ok(());
-    }
-    let msg: Message = serde_json::from_str(buf.trim()).context("parse incoming json")?;
+    };
     match msg {
         Message::Heartbeat { leader, term_end_unix, term } => {
             let mut ns = shared.write().await;
@@ -408,9 +1153,20 @@ async fn handle_connection(
                     if ns.state == State::Leader {
                         println!("Stepping down: received heartbeat from higher term {}", term);
                         ns.state = State::Follower;
+                        ns.metrics.leader_steps_taken += 1;
                     }
                 }
                 
+                // A live leader for our election term (or later) settles it; stop campaigning
+                if ns.is_electing {
+                    println!(
+                        "[ELECTION] Heartbeat from {} for term {}, abandoning our election",
+                        leader, term
+                    );
+                    ns.is_electing = false;
+                }
+
+                ns.metrics.heartbeats_received += 1;
                 ns.last_heartbeat = Some(Instant::now());
                 ns.leader = Some(leader.clone());
                 ns.term_end = Some(Instant::now() + StdDuration::from_millis(0));
@@ -423,29 +1179,54 @@ async fn handle_connection(
             } else {
                 println!("Rejected heartbeat from term {} (current term: {})", term, ns.current_term);
             }
+            ns.persist_snapshot();
+            drop(ns);
 
             let resp = Message::Ping;
-            let s = serde_json::to_string(&resp)? + "\n";
-            w.write_all(s.as_bytes()).await?;
+            framing.write_message(&mut stream, &resp).await?;
         }
-        Message::GetCpu { term, initiator_addr, initiator_cpu } => {
-            let snapshot_val = {
+        Message::GetCpu { term, initiator_addr, .. } => {
+            let (snapshot_val, vote_denied) = {
                 let mut ns = shared.write().await;
                 
                 if term > ns.current_term {
                     ns.current_term = term;
                     ns.cpu_snapshot = *cpu.read().await;
+                    ns.persist_snapshot();
                 }
-                
-                ns.cpu_snapshot
+
+                let current_term = ns.current_term;
+                ns.votes_cast
+                    .retain(|t, _| t + VOTE_HISTORY_TERMS >= current_term);
+
+                let (cpu_val, vote_denied) = match ns.votes_cast.get(&term) {
+                    Some(voted_for) if *voted_for != initiator_addr => {
+                        println!(
+                            "[VOTE] Already answered {} for term {}, denying {}",
+                            voted_for, term, initiator_addr
+                        );
+                        (f32::MAX, true)
+                    }
+                    Some(_) => (ns.cpu_snapshot, false),
+                    None => {
+                        ns.votes_cast.insert(term, initiator_addr.clone());
+                        (ns.cpu_snapshot, false)
+                    }
+                };
+                (if ns.storage_probe_failed { f32::MAX } else { cpu_val }, vote_denied)
             };
             
-            let resp = Message::CpuResp { cpu_percent: snapshot_val, addr: peer.to_string(), term };
-            let s = serde_json::to_string(&resp)? + "\n";
-            w.write_all(s.as_bytes()).await?;
+            let resp = Message::CpuResp { cpu_percent: snapshot_val, addr: peer.to_string(), term, vote_denied };
+            framing.write_message(&mut stream, &resp).await?;
         }
 
         Message::LeaderAnnounce { leader, term_end_unix, term } => {
+            // Probe before taking the lock; a slow backend shouldn't stall the node
+            let storage_check = if leader == this_node {
+                Some(probe_storage(&user_directory).await)
+            } else {
+                None
+            };
             let mut ns = shared.write().await;
 
             if term >= ns.current_term {
@@ -457,12 +1238,27 @@ async fn handle_connection(
                             term
                         );
                         ns.state = State::Follower;
+                        ns.metrics.leader_steps_taken += 1;
                     }
                 }
 
                 let is_self = leader == this_node;
+                if let Some(check) = &storage_check {
+                    ns.storage_probe_failed = check.is_err();
+                }
 
-                if is_self {
+                if let Some(Err(reason)) = &storage_check {
+                    // Leave leader unset so the next election timeout picks someone else
+                    println!(
+                        "[LEADER_ANNOUNCE] Elected for term {} but storage probe failed ({}), stepping down",
+                        term, reason
+                    );
+                    if ns.state == State::Leader {
+                        ns.metrics.leader_steps_taken += 1;
+                    }
+                    ns.state = State::Follower;
+                    ns.leader = None;
+                } else if is_self {
                     println!(
                         "[LEADER_ANNOUNCE] I ({}) am elected leader for term {}",
                         leader, term
@@ -474,6 +1270,9 @@ async fn handle_connection(
                         "[LEADER_ANNOUNCE] New leader {} for term {} (I become follower)",
                         leader, term
                     );
+                    if ns.state == State::Leader {
+                        ns.metrics.leader_steps_taken += 1;
+                    }
                     ns.state = State::Follower;
                     ns.leader = Some(leader.clone());
                 }
@@ -492,17 +1291,30 @@ async fn handle_connection(
                     term, ns.current_term
                 );
             }
+            ns.persist_snapshot();
+            drop(ns);
 
             let resp = Message::Ping;
-            let s = serde_json::to_string(&resp)? + "\n";
-            w.write_all(s.as_bytes()).await?;
+            framing.write_message(&mut stream, &resp).await?;
         }
 
-        Message::CpuResp { .. } => {}
+        Message::StatusReq => {
+            let resp = {
+                let ns = shared.read().await;
+                Message::StatusResp {
+                    state: ns.state.clone(),
+                    term: ns.current_term,
+                    leader: ns.leader.clone(),
+                    http_port: ns.http_port,
+                }
+            };
+            framing.write_message(&mut stream, &resp).await?;
+        }
+
+        Message::CpuResp { .. } | Message::StatusResp { .. } => {}
         Message::Ping => {
             let resp = Message::Ping;
-            let s = serde_json::to_string(&resp)? + "\n";
-            w.write_all(s.as_bytes()).await?;
+            framing.write_message(&mut stream, &resp).await?;
         }
     }
     Ok(())
@@ -514,28 +1326,44 @@ async fn run_election(
     cfg: &Config,
     shared: Arc<RwLock<NodeState>>,
     cpu: Arc<RwLock<f32>>,
-) -> anyhow::Result<()> {
-    let (election_term, self_cpu_snapshot) = {
+    user_directory: Arc<UserDirectory>,
+) -> anyhow::Result<ElectionOutcome> {
+    let (election_term, self_cpu_snapshot, mut durable) = {
         let mut ns = shared.write().await;
         ns.current_term += 1;
+        ns.is_electing = true;
+        ns.persist_snapshot();
         ns.cpu_snapshot = *cpu.read().await;
-        (ns.current_term, ns.cpu_snapshot)
+        ns.metrics.elections_initiated += 1;
+        (ns.current_term, ns.cpu_snapshot, ns.durable_snapshots())
     };
+
+    // Peers must not see the new term until it survives a crash, or a restart
+    // could bring this node back with a term it already used
+    if let Err(e) = wait_term_durable(&mut durable, election_term, cfg.net_timeout_ms).await {
+        shared.write().await.is_electing = false;
+        return Err(e.context("election aborted"));
+    }
     
     println!("Starting election from {} for term {} with CPU snapshot: {}%", 
              this_addr_str, election_term, self_cpu_snapshot);
     
     let mut collected: HashMap<String, f32> = HashMap::new();
     collected.insert(this_addr_str.to_string(), self_cpu_snapshot);
+    // Our own vote plus every peer that didn't already vote for someone else
+    let mut votes = 1;
 
     for p in peers.iter() {
         let p_s = p.to_string();
         if p_s == this_addr_str {
             continue;
         }
-        match request_cpu(p, cfg.net_timeout_ms, election_term, this_addr_str, self_cpu_snapshot).await {
-            Ok(val) => {
-                collected.insert(p.to_string(), val);
+        match request_cpu(p, cfg.net_timeout_ms, election_term, this_addr_str, self_cpu_snapshot, &shared).await {
+            Ok(vote) => {
+                if !vote.denied {
+                    votes += 1;
+                }
+                collected.insert(p.to_string(), vote.cpu_percent);
             }
             Err(e) => {
                 eprintln!("failed to get cpu from {}: {}", p, e);
@@ -544,20 +1372,56 @@ async fn run_election(
         sleep(StdDuration::from_millis(cfg.election_retry_ms)).await;
     }
 
-    let mut chosen = None;
-    for (addr, cpu_val) in collected.iter() {
-        match &chosen {
-            None => chosen = Some((addr.clone(), *cpu_val)),
-            Some((caddr, cval)) => {
-                if *cpu_val < *cval || (*cpu_val == *cval && addr < caddr) {
-                    chosen = Some((addr.clone(), *cpu_val));
-                }
-            }
+    // handle_connection clears is_electing when a leader's heartbeat beat us to it
+    if !shared.read().await.is_electing {
+        println!("[ELECTION] Term {} already has a leader, not announcing one", election_term);
+        return Ok(ElectionOutcome::Superseded);
+    }
+
+    // Without a majority this node may be on the minority side of a partition;
+    // electing a leader here would give the cluster two. Denials don't count, or
+    // two concurrent initiators could each reach quorum on the other's denials.
+    let quorum = min_quorum(cfg, peers);
+    if votes < quorum {
+        println!(
+            "[ELECTION] insufficient votes for quorum ({}/{}, {} answered), aborting election",
+            votes,
+            quorum,
+            collected.len()
+        );
+        let mut ns = shared.write().await;
+        // Only undo our own bump; a higher term seen meanwhile stays
+        if ns.current_term == election_term {
+            ns.current_term -= 1;
         }
+        ns.is_electing = false;
+        ns.persist_snapshot();
+        return Ok(ElectionOutcome::NoQuorum);
     }
 
-    if let Some((leader_addr, _)) = chosen {
+    let mut chosen = pick_leader(&collected);
+    if chosen.as_ref().is_some_and(|(addr, _)| addr == this_addr_str) {
+        let probe = probe_storage(&user_directory).await;
+        shared.write().await.storage_probe_failed = probe.is_err();
+        if let Err(reason) = probe {
+            println!(
+                "[ELECTION] Storage probe failed ({}), not taking leadership for term {}",
+                reason, election_term
+            );
+            collected.remove(this_addr_str);
+            chosen = pick_leader(&collected);
+        }
+    }
+
+    if chosen.is_none() {
+        println!("[ELECTION] No eligible leader for term {}", election_term);
+    }
+
+    if let Some((leader_addr, leader_cpu)) = chosen {
         println!("Election result: leader -> {} (term {})", leader_addr, election_term);
+        let tiebreak = collected
+            .iter()
+            .any(|(addr, cpu_val)| *addr != leader_addr && *cpu_val == leader_cpu);
         let term_end_unix =
             (Utc::now() + ChronoDuration::milliseconds(cfg.leader_term_ms as i64)).timestamp() as u64;
 
@@ -568,36 +1432,66 @@ async fn run_election(
                 ns.leader = Some(this_addr_str.to_string());
                 ns.term_end = Some(Instant::now() + StdDuration::from_millis(cfg.leader_term_ms));
                 ns.last_heartbeat = Some(Instant::now());
+                ns.metrics.elections_won += 1;
+                if tiebreak {
+                    ns.metrics.cpu_tiebreaks_resolved += 1;
+                }
+                ns.persist_snapshot();
             }
             println!(
                 "[ELECTION] I ({}) won term {}. Broadcasting LeaderAnnounce to peers",
                 this_addr_str, election_term
             );
-            broadcast_leader(&peers, &this_addr_str, term_end_unix, election_term, cfg.net_timeout_ms).await;
+            broadcast_leader(peers, this_addr_str, term_end_unix, election_term, cfg.net_timeout_ms, &shared).await;
         } else {
             {
                 let mut ns = shared.write().await;
+                if ns.state == State::Leader {
+                    ns.metrics.leader_steps_taken += 1;
+                }
                 ns.state = State::Follower;
                 ns.leader = Some(leader_addr.clone());
                 ns.term_end = Some(Instant::now() + StdDuration::from_millis(cfg.leader_term_ms));
                 ns.last_heartbeat = Some(Instant::now());
+                ns.metrics.elections_lost += 1;
+                if tiebreak {
+                    ns.metrics.cpu_tiebreaks_resolved += 1;
+                }
+                ns.persist_snapshot();
             }
             println!(
                 "[ELECTION] {} won term {} (I am {}). Broadcasting LeaderAnnounce",
                 leader_addr, election_term, this_addr_str
             );
-            broadcast_leader(&peers, &leader_addr, term_end_unix, election_term, cfg.net_timeout_ms).await;
+            broadcast_leader(peers, &leader_addr, term_end_unix, election_term, cfg.net_timeout_ms, &shared).await;
         }
     }
 
-    Ok(())
+    shared.write().await.is_electing = false;
+    Ok(ElectionOutcome::Decided)
+}
+
+/// A peer's answer to GetCpu
+struct CpuVote {
+    cpu_percent: f32,
+    /// The peer already voted for another initiator this term
+    denied: bool,
 }
 
-async fn request_cpu(peer: &SocketAddr, timeout_ms: u64, term: u64, initiator_addr: &str, initiator_cpu: f32) -> anyhow::Result<f32> {
+async fn request_cpu(
+    peer: &SocketAddr,
+    timeout_ms: u64,
+    term: u64,
+    initiator_addr: &str,
+    initiator_cpu: f32,
+    shared: &Arc<RwLock<NodeState>>,
+) -> anyhow::Result<CpuVote> {
     let addr = peer.to_string();
+    let started = Instant::now();
+    let framing = shared.read().await.framing.clone();
     println!("[CPU Request] Connecting to {}", addr);
     let connect =
-        tokio::time::timeout(StdDuration::from_millis(timeout_ms), TcpStream::connect(peer)).await;
+        tokio::time::timeout(StdDuration::from_millis(timeout_ms), transport::connect(peer)).await;
     let mut stream = match connect {
         Ok(Ok(s)) => {
             println!("[CPU Request] Connected to {}", addr);
@@ -614,24 +1508,27 @@ async fn request_cpu(peer: &SocketAddr, timeout_ms: u64, term: u64, initiator_ad
         initiator_addr: initiator_addr.to_string(),
         initiator_cpu 
     };
-    let s = serde_json::to_string(&msg)? + "\n";
-    stream.write_all(s.as_bytes()).await?;
+    framing.write_message(&mut stream, &msg).await?;
     println!("[CPU Request] Sent GetCpu to {}", addr);
 
-    let mut reader = BufReader::new(stream);
-    let mut buf = String::new();
-    let n = tokio::time::timeout(StdDuration::from_millis(timeout_ms), reader.read_line(&mut buf))
-        .await??;
+    let resp = tokio::time::timeout(
+        StdDuration::from_millis(timeout_ms),
+        framing.read_message::<_, Message>(&mut stream),
+    )
+    .await??;
 
-    if n == 0 {
+    let Some(resp) = resp else {
         eprintln!("[CPU Request] No response from {}", addr);
         anyhow::bail!("no response from {}", addr);
-    }
+    };
+    record_latency(shared, peer, started).await;
 
-    let resp: Message = serde_json::from_str(buf.trim())?;
-    if let Message::CpuResp { cpu_percent, term, .. } = resp {
-        println!("[CPU Request] Received CPU {}% from {} (term: {})", cpu_percent, addr, term);
-        Ok(cpu_percent)
+    if let Message::CpuResp { cpu_percent, term, vote_denied, .. } = resp {
+        println!(
+            "[CPU Request] Received CPU {}% from {} (term: {}, denied: {})",
+            cpu_percent, addr, term, vote_denied
+        );
+        Ok(CpuVote { cpu_percent, denied: vote_denied })
     }
     else {
         eprintln!("[CPU Request] Unexpected response from {}", addr);
@@ -639,12 +1536,65 @@ async fn request_cpu(peer: &SocketAddr, timeout_ms: u64, term: u64, initiator_ad
     }
 }
 
+/// Ask every peer for its status and cache the results for `GET /cluster`
+async fn poll_cluster_status(peers: &[SocketAddr], timeout_ms: u64, shared: &Arc<RwLock<NodeState>>) {
+    let statuses = join_all(peers.iter().map(|p| request_status(p, timeout_ms, shared))).await;
+    shared.write().await.cluster_status = statuses;
+}
+
+/// Ask a peer for its role and term; unreachable peers are reported, not errors
+async fn request_status(peer: &SocketAddr, timeout_ms: u64, shared: &Arc<RwLock<NodeState>>) -> PeerStatus {
+    let unreachable = PeerStatus {
+        addr: peer.to_string(),
+        state: None,
+        term: None,
+        leader: None,
+        http_port: None,
+        reachable: false,
+    };
+
+    let started = Instant::now();
+    let framing = shared.read().await.framing.clone();
+    let exchange = async {
+        let mut stream = transport::connect(peer).await?;
+        framing.write_message(&mut stream, &Message::StatusReq).await?;
+        framing.read_message::<_, Message>(&mut stream).await
+    };
+
+    match tokio::time::timeout(StdDuration::from_millis(timeout_ms), exchange).await {
+        Ok(Ok(Some(Message::StatusResp { state, term, leader, http_port }))) => {
+            record_latency(shared, peer, started).await;
+            PeerStatus {
+                state: Some(state),
+                term: Some(term),
+                leader,
+                http_port,
+                reachable: true,
+                ..unreachable
+            }
+        }
+        Ok(Ok(_)) => {
+            debug!("[Status] Unexpected or empty response from {}", peer);
+            unreachable
+        }
+        Ok(Err(e)) => {
+            debug!("[Status] Failed to query {}: {}", peer, e);
+            unreachable
+        }
+        Err(_) => {
+            debug!("[Status] Timeout querying {}", peer);
+            unreachable
+        }
+    }
+}
+
 async fn broadcast_leader(
     peers: &[SocketAddr],
     leader: &str,
     term_end_unix: u64,
     term: u64,
     timeout_ms: u64,
+    shared: &Arc<RwLock<NodeState>>,
 ) {
     for p in peers.iter() {
         let p_s = p.to_string();
@@ -658,7 +1608,7 @@ async fn broadcast_leader(
             term_end_unix,
             term,
         };
-        let _ = send_message(p, &msg, timeout_ms).await;
+        let _ = send_message_with_retry(p, &msg, timeout_ms, 3, shared).await;
     }
 }
 
@@ -680,15 +1630,24 @@ async fn send_heartbeat_to_peers(
             continue;
         }
         let msg = Message::Heartbeat { leader: leader.to_string(), term_end_unix, term: current_term };
-        let _ = send_message(p, &msg, cfg.net_timeout_ms).await;
+        if send_message_with_retry(p, &msg, cfg.net_timeout_ms, 1, &shared).await.is_ok() {
+            shared.write().await.metrics.heartbeats_sent += 1;
+        }
     }
 }
 
-async fn send_message(peer: &SocketAddr, msg: &Message, timeout_ms: u64) -> anyhow::Result<()> {
+async fn send_message(
+    peer: &SocketAddr,
+    msg: &Message,
+    timeout_ms: u64,
+    shared: &Arc<RwLock<NodeState>>,
+) -> anyhow::Result<()> {
     let addr = peer.to_string();
+    let started = Instant::now();
+    let framing = shared.read().await.framing.clone();
     println!("[Send] Connecting to {}", addr);
     let connect =
-        tokio::time::timeout(StdDuration::from_millis(timeout_ms), TcpStream::connect(peer)).await;
+        tokio::time::timeout(StdDuration::from_millis(timeout_ms), transport::connect(peer)).await;
 
     let mut stream = match connect {
         Ok(Ok(s)) => {
@@ -701,19 +1660,499 @@ async fn send_message(peer: &SocketAddr, msg: &Message, timeout_ms: u64) -> anyh
         }
     };
 
-    let s = serde_json::to_string(msg)? + "\n";
-    stream.write_all(s.as_bytes()).await?;
+    framing.write_message(&mut stream, msg).await?;
     println!("[Send] Sent message to {}", addr);
 
-    let mut reader = BufReader::new(stream);
-    let mut buf = String::new();
-    let res = tokio::time::timeout(StdDuration::from_millis(timeout_ms), reader.read_line(&mut buf)).await;
+    let res = tokio::time::timeout(
+        StdDuration::from_millis(timeout_ms),
+        framing.read_message::<_, Message>(&mut stream),
+    )
+    .await;
 
+    // Every message sent this way is answered, so no reply means it may not have arrived
     match res {
-        Ok(Ok(0)) => println!("[Send] No response received from {}", addr),
-        Ok(Ok(_)) => println!("[Send] Received response from {}", addr),
-        _ => eprintln!("[Send] Timeout or error receiving response from {}", addr),
+        Ok(Ok(Some(_))) => {
+            println!("[Send] Received response from {}", addr);
+            record_latency(shared, peer, started).await;
+            Ok(())
+        }
+        Ok(Ok(None)) => {
+            eprintln!("[Send] No response received from {}", addr);
+            anyhow::bail!("{} closed the connection without replying", addr)
+        }
+        Ok(Err(e)) => {
+            eprintln!("[Send] Error receiving response from {}", addr);
+            Err(e.context(format!("read reply from {}", addr)))
+        }
+        Err(_) => {
+            eprintln!("[Send] Timeout receiving response from {}", addr);
+            anyhow::bail!("no reply from {} within {} ms", addr, timeout_ms)
+        }
     }
+}
 
-    Ok(())
+/// Send a message, retrying up to `max_retries` times with exponential backoff
+/// (100ms, 200ms, 400ms, ...) so a transient blip doesn't drop an announce
+async fn send_message_with_retry(
+    peer: &SocketAddr,
+    msg: &Message,
+    timeout_ms: u64,
+    max_retries: u32,
+    shared: &Arc<RwLock<NodeState>>,
+) -> anyhow::Result<()> {
+    let mut attempt: u32 = 0;
+    loop {
+        match send_message(peer, msg, timeout_ms, shared).await {
+            Ok(()) => return Ok(()),
+            Err(e) if attempt < max_retries => {
+                let backoff_ms = 100 * 2u64.pow(attempt);
+                attempt += 1;
+                debug!(
+                    "[Retry] Send to {} failed ({}), retry {}/{} in {} ms",
+                    peer, e, attempt, max_retries, backoff_ms
+                );
+                sleep(StdDuration::from_millis(backoff_ms)).await;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node_state() -> Arc<RwLock<NodeState>> {
+        let snapshot_path = std::env::temp_dir()
+            .join(format!("node-snapshot-{}.json", uuid::Uuid::new_v4()))
+            .to_string_lossy()
+            .into_owned();
+        node_state_at(snapshot_path)
+    }
+
+    /// A fresh node with its snapshot writer running, as main sets it up
+    fn node_state_at(snapshot_path: String) -> Arc<RwLock<NodeState>> {
+        let ns = NodeState::new(snapshot_path.clone(), None);
+        let (updates, durable) = ns.snapshot_channels();
+        tokio::spawn(run_snapshot_writer(snapshot_path, updates, durable));
+        Arc::new(RwLock::new(ns))
+    }
+
+    /// Run handle_connection on an in-memory stream after `send` writes to the other end;
+    /// returns the handler's result and the stream to read its reply from
+    async fn handle_with<F, Fut>(shared: &Arc<RwLock<NodeState>>, send: F) -> (anyhow::Result<()>, tokio::io::DuplexStream)
+    where
+        F: FnOnce(tokio::io::DuplexStream) -> Fut,
+        Fut: std::future::Future<Output = tokio::io::DuplexStream>,
+    {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let directory = Arc::new(registration::test_util::directory());
+        let handler = handle_connection(
+            Box::new(server),
+            "127.0.0.1:5002".parse().unwrap(),
+            shared.clone(),
+            Arc::new(RwLock::new(0.0)),
+            "127.0.0.1:5001".to_string(),
+            directory,
+        );
+        tokio::join!(handler, send(client))
+    }
+
+    /// Deliver `msg` to handle_connection and return its reply
+    async fn deliver(shared: &Arc<RwLock<NodeState>>, msg: &Message) -> Option<Message> {
+        let framing = shared.read().await.framing.clone();
+        let writer = framing.clone();
+        let (result, mut client) = handle_with(shared, |mut client| async move {
+            writer.write_message(&mut client, msg).await.unwrap();
+            client
+        })
+        .await;
+        result.unwrap();
+        framing.read_message::<_, Message>(&mut client).await.unwrap()
+    }
+
+    /// A peer that answers every StatusReq with the given role and term
+    async fn mock_status_peer(state: State, term: u64) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let framing = framing::Framing::default();
+            while let Ok((mut stream, _)) = listener.accept().await {
+                if let Ok(Some(Message::StatusReq)) = framing.read_message::<_, Message>(&mut stream).await {
+                    let resp = Message::StatusResp {
+                        state: state.clone(),
+                        term,
+                        leader: Some("127.0.0.1:5001".to_string()),
+                        http_port: Some(3002),
+                    };
+                    let _ = framing.write_message(&mut stream, &resp).await;
+                }
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn cluster_poll_assembles_peer_statuses() {
+        let follower = mock_status_peer(State::Follower, 7).await;
+        let down = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            listener.local_addr().unwrap()
+        };
+        let shared = node_state();
+
+        poll_cluster_status(&[follower, down], 1000, &shared).await;
+
+        let ns = shared.read().await;
+        assert_eq!(ns.cluster_status.len(), 2);
+        let up = &ns.cluster_status[0];
+        assert_eq!(up.addr, follower.to_string());
+        assert!(up.reachable);
+        assert_eq!(up.state, Some(State::Follower));
+        assert_eq!(up.term, Some(7));
+        assert_eq!(up.http_port, Some(3002));
+        let unreachable = &ns.cluster_status[1];
+        assert_eq!(unreachable.addr, down.to_string());
+        assert!(!unreachable.reachable);
+        assert_eq!(unreachable.term, None);
+    }
+
+    /// A peer that answers every GetCpu with `cpu_percent`, or a denial, and
+    /// acknowledges anything else
+    async fn mock_cpu_peer(cpu_percent: f32, vote_denied: bool) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let framing = framing::Framing::default();
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let resp = match framing.read_message::<_, Message>(&mut stream).await {
+                    Ok(Some(Message::GetCpu { term, .. })) => Message::CpuResp {
+                        cpu_percent: if vote_denied { f32::MAX } else { cpu_percent },
+                        addr: addr.to_string(),
+                        term,
+                        vote_denied,
+                    },
+                    Ok(Some(_)) => Message::Ping,
+                    _ => continue,
+                };
+                let _ = framing.write_message(&mut stream, &resp).await;
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn denied_votes_do_not_count_toward_quorum() {
+        let cfg = test_config("");
+        let directory = Arc::new(registration::test_util::directory());
+
+        // Self plus a denial would have met a quorum of 2 by counting answers
+        let denying = mock_cpu_peer(1.0, true).await;
+        let shared = node_state();
+        let outcome = run_election(&[denying], &cfg.bind_addr, &cfg, shared.clone(), Arc::new(RwLock::new(5.0)), directory.clone())
+            .await
+            .unwrap();
+        assert_eq!(outcome, ElectionOutcome::NoQuorum);
+        assert_eq!(shared.read().await.state, State::Follower);
+
+        let granting = mock_cpu_peer(90.0, false).await;
+        let shared = node_state();
+        let outcome = run_election(&[granting], &cfg.bind_addr, &cfg, shared.clone(), Arc::new(RwLock::new(5.0)), directory)
+            .await
+            .unwrap();
+        assert_eq!(outcome, ElectionOutcome::Decided);
+        assert_eq!(shared.read().await.state, State::Leader);
+    }
+
+    #[tokio::test]
+    async fn node_with_failing_storage_declines_leadership() {
+        let cfg = test_config("");
+        let shared = node_state();
+        let directory = Arc::new(registration::test_util::directory());
+        registration::test_util::set_storage_unavailable(&directory, true);
+
+        // No peers, so this node would otherwise win on its own vote
+        let outcome = run_election(
+            &[],
+            &cfg.bind_addr,
+            &cfg,
+            shared.clone(),
+            Arc::new(RwLock::new(5.0)),
+            directory.clone(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(outcome, ElectionOutcome::Decided);
+        {
+            let ns = shared.read().await;
+            assert_eq!(ns.state, State::Follower);
+            assert_eq!(ns.leader, None);
+            assert!(ns.storage_probe_failed);
+        }
+
+        registration::test_util::set_storage_unavailable(&directory, false);
+        run_election(&[], &cfg.bind_addr, &cfg, shared.clone(), Arc::new(RwLock::new(5.0)), directory)
+            .await
+            .unwrap();
+        let ns = shared.read().await;
+        assert_eq!(ns.state, State::Leader);
+        assert!(!ns.storage_probe_failed);
+    }
+
+    #[tokio::test]
+    async fn heartbeat_with_wrong_hmac_is_ignored() {
+        let shared = node_state();
+        {
+            let mut ns = shared.write().await;
+            ns.framing = framing::Framing::new(Some("test-cluster-secret")).unwrap();
+            ns.current_term = 3;
+        }
+
+        let heartbeat = Message::Heartbeat {
+            leader: "127.0.0.1:5003".to_string(),
+            term_end_unix: 0,
+            term: 9,
+        };
+        let forged = serde_json::json!({
+            "body": serde_json::to_string(&heartbeat).unwrap(),
+            "mac": hex::encode([0u8; 32]),
+        });
+        let (result, _client) = handle_with(&shared, |mut client| async move {
+            framing::write_frame(&mut client, &forged).await.unwrap();
+            client
+        })
+        .await;
+        assert!(result.is_err());
+
+        {
+            let ns = shared.read().await;
+            assert_eq!(ns.current_term, 3);
+            assert_eq!(ns.leader, None);
+            assert_eq!(ns.metrics.heartbeats_received, 0);
+        }
+
+        // The same heartbeat with a valid tag is accepted
+        deliver(&shared, &heartbeat).await;
+        assert_eq!(shared.read().await.current_term, 9);
+    }
+
+    #[tokio::test]
+    async fn same_term_heartbeat_ends_election() {
+        let shared = node_state();
+        {
+            let mut ns = shared.write().await;
+            ns.current_term = 5;
+            ns.is_electing = true;
+        }
+
+        let heartbeat = Message::Heartbeat {
+            leader: "127.0.0.1:5003".to_string(),
+            term_end_unix: 0,
+            term: 5,
+        };
+        assert!(matches!(deliver(&shared, &heartbeat).await, Some(Message::Ping)));
+
+        let ns = shared.read().await;
+        assert!(!ns.is_electing);
+        assert_eq!(ns.current_term, 5);
+        assert_eq!(ns.leader.as_deref(), Some("127.0.0.1:5003"));
+    }
+
+    /// Minimal config with a 1000-4000 ms election timeout
+    fn test_config(extra: &str) -> Config {
+        toml::from_str(&format!(
+            r#"
+            bind_addr = "127.0.0.1:5001"
+            peers = ["127.0.0.1:5001", "127.0.0.1:5002", "127.0.0.1:5003"]
+            heartbeat_interval_ms = 100
+            election_timeout_min_ms = 1000
+            election_timeout_max_ms = 4000
+            leader_term_ms = 60000
+            net_timeout_ms = 500
+            cpu_refresh_ms = 1000
+            election_retry_ms = 1000
+            {}
+            "#,
+            extra
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn average_cpu_usage_handles_empty_and_out_of_range() {
+        assert_eq!(average_cpu_usage(&[]), None);
+        assert_eq!(average_cpu_usage(&[f32::NAN, 10.0]), None);
+        assert_eq!(average_cpu_usage(&[20.0, 40.0]), Some(30.0));
+        assert_eq!(average_cpu_usage(&[150.0, 250.0]), Some(100.0));
+        assert_eq!(average_cpu_usage(&[-5.0, -15.0]), Some(0.0));
+    }
+
+    #[test]
+    fn peers_are_normalized_and_exclude_this_node() {
+        let this_addr: SocketAddr = "127.0.0.1:5001".parse().unwrap();
+        let configured: Vec<String> = [
+            "127.0.0.1:5001",
+            "127.0.0.1:5002",
+            "127.0.0.1:5002",
+            "[0:0:0:0:0:0:0:1]:5003",
+            "[::1]:5003",
+        ]
+        .iter()
+        .map(|peer| peer.to_string())
+        .collect();
+
+        let peers = resolve_peers(&configured, this_addr).unwrap();
+        let peers: Vec<String> = peers.iter().map(SocketAddr::to_string).collect();
+        assert_eq!(peers, ["127.0.0.1:5002", "[::1]:5003"]);
+        assert!(resolve_peers(&["not an address".to_string()], this_addr).is_err());
+    }
+
+    #[test]
+    fn advertise_addr_defaults_to_bind_addr() {
+        let (bind, advertise) = node_addrs(&test_config("")).unwrap();
+        assert_eq!(bind, advertise);
+        assert_eq!(advertise.to_string(), "127.0.0.1:5001");
+    }
+
+    #[test]
+    fn wildcard_bind_needs_advertise_addr() {
+        let mut cfg = test_config("");
+        cfg.bind_addr = "0.0.0.0:5001".to_string();
+        assert!(node_addrs(&cfg).is_err());
+    }
+
+    #[test]
+    fn self_exclusion_uses_advertise_addr() {
+        let mut cfg = test_config(r#"advertise_addr = "127.0.0.1:5001""#);
+        cfg.bind_addr = "0.0.0.0:5001".to_string();
+        let (bind, advertise) = node_addrs(&cfg).unwrap();
+        assert_eq!(bind.to_string(), "0.0.0.0:5001");
+        assert_eq!(advertise.to_string(), "127.0.0.1:5001");
+
+        let peers = resolve_peers(&cfg.peers, advertise).unwrap();
+        assert!(!peers.contains(&advertise));
+        assert_eq!(peers.len(), 2);
+    }
+
+    #[test]
+    fn backoff_timeouts_grow_with_failed_elections_up_to_max() {
+        let cfg = test_config(r#"election_jitter = "backoff""#);
+        let windows: Vec<u64> = (0..10).map(|failed| election_window_max(&cfg, failed)).collect();
+        assert_eq!(windows[0], 1750);
+        assert!(windows.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert_eq!(*windows.last().unwrap(), cfg.election_timeout_max_ms);
+        assert_eq!(election_window_max(&cfg, u32::MAX), cfg.election_timeout_max_ms);
+
+        for failed in [0, 1, 2, 5, u32::MAX] {
+            for _ in 0..100 {
+                let timeout = random_election_timeout(&cfg, failed);
+                assert!(timeout >= cfg.election_timeout_min_ms);
+                assert!(timeout <= election_window_max(&cfg, failed));
+            }
+        }
+    }
+
+    #[test]
+    fn uniform_jitter_ignores_failed_elections() {
+        let cfg = test_config("");
+        assert_eq!(election_window_max(&cfg, 0), cfg.election_timeout_max_ms);
+        assert_eq!(election_window_max(&cfg, 5), cfg.election_timeout_max_ms);
+    }
+
+    #[test]
+    fn leaderless_retry_gap_backs_off_to_max_timeout() {
+        let cfg = test_config("");
+        let gaps: Vec<u64> = (0..8).map(|failed| leaderless_retry_gap(&cfg, failed)).collect();
+        assert_eq!(gaps[0], 500);
+        assert!(gaps.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert_eq!(*gaps.last().unwrap(), cfg.election_timeout_max_ms);
+        assert_eq!(leaderless_retry_gap(&cfg, u32::MAX), cfg.election_timeout_max_ms);
+    }
+
+    #[tokio::test]
+    async fn flush_writes_snapshot_file() {
+        let shared = node_state();
+        let path = {
+            let mut ns = shared.write().await;
+            ns.current_term = 7;
+            ns.state = State::Leader;
+            ns.leader = Some("127.0.0.1:5001".to_string());
+            ns.snapshot_path.clone()
+        };
+
+        flush_snapshot(&shared).await.unwrap();
+
+        let snapshot = NodeState::load_snapshot(&path).expect("snapshot written");
+        assert_eq!(snapshot.term, 7);
+        assert_eq!(snapshot.state, State::Leader);
+        assert_eq!(snapshot.leader.as_deref(), Some("127.0.0.1:5001"));
+        assert!(!std::path::Path::new(&path).with_extension("json.tmp").exists());
+        let _ = fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn persisted_snapshot_is_written_by_writer_task() {
+        let shared = node_state();
+        let (path, mut durable) = {
+            let mut ns = shared.write().await;
+            ns.current_term = 3;
+            ns.persist_snapshot();
+            (ns.snapshot_path.clone(), ns.durable_snapshots())
+        };
+
+        wait_term_durable(&mut durable, 3, 1000).await.unwrap();
+        assert_eq!(NodeState::load_snapshot(&path).expect("snapshot written").term, 3);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn failed_snapshot_write_blocks_election_and_is_retried() {
+        // A file where the snapshot's directory should be makes every write fail
+        let blocker = std::env::temp_dir().join(format!("snapshot-blocker-{}", uuid::Uuid::new_v4()));
+        fs::write(&blocker, b"").unwrap();
+        let path = blocker.join("node_snapshot.json").to_string_lossy().into_owned();
+        let cfg = test_config("");
+        let shared = node_state_at(path.clone());
+        let directory = Arc::new(registration::test_util::directory());
+
+        let result = run_election(&[], &cfg.bind_addr, &cfg, shared.clone(), Arc::new(RwLock::new(5.0)), directory).await;
+        assert!(result.is_err(), "election went ahead with an unsaved term");
+        let mut durable = {
+            let ns = shared.read().await;
+            assert_eq!(ns.state, State::Follower);
+            assert!(!ns.is_electing);
+            ns.durable_snapshots()
+        };
+        assert!(durable.borrow().is_none());
+
+        // The writer keeps retrying the same snapshot until the disk recovers
+        fs::remove_file(&blocker).unwrap();
+        wait_term_durable(&mut durable, 1, 3000).await.unwrap();
+        assert_eq!(NodeState::load_snapshot(&path).expect("snapshot written").term, 1);
+        let _ = fs::remove_dir_all(&blocker);
+    }
+
+    #[tokio::test]
+    async fn unanswered_message_is_retried_and_fails() {
+        let shared = node_state();
+        // Accepts every connection and reads the message, but never replies
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer = listener.local_addr().unwrap();
+        let accepted = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = accepted.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                tokio::spawn(async move {
+                    let _ = framing::Framing::default().read_message::<_, Message>(&mut stream).await;
+                    sleep(StdDuration::from_secs(5)).await;
+                });
+            }
+        });
+
+        let msg = Message::Heartbeat { leader: "127.0.0.1:5001".to_string(), term_end_unix: 0, term: 1 };
+        assert!(send_message(&peer, &msg, 100, &shared).await.is_err());
+        assert!(send_message_with_retry(&peer, &msg, 100, 1, &shared).await.is_err());
+        assert_eq!(accepted.load(std::sync::atomic::Ordering::SeqCst), 3);
+    }
 }
@@ -0,0 +1,47 @@
+//! LAN discovery: advertise this node as a `_cloud-steg._tcp` mDNS service
+//!
+//! Clients on the same network can browse for the service instead of being
+//! told a server address. Each record carries the node's HTTP port (the SRV
+//! port) and its election address in the TXT properties; a client asks the
+//! node's `/` endpoint whether it is the leader.
+
+use anyhow::Context;
+use mdns_sd::{ServiceDaemon, ServiceInfo};
+use std::net::SocketAddr;
+use tracing::info;
+
+pub const SERVICE_TYPE: &str = "_cloud-steg._tcp.local.";
+
+/// Register this node's service record. The returned daemon answers queries on
+/// its own thread and must be kept alive for as long as the node should be found.
+pub fn advertise(election_addr: SocketAddr, http_port: u16) -> anyhow::Result<ServiceDaemon> {
+    let daemon = ServiceDaemon::new().context("start mDNS daemon")?;
+
+    // Instance and host names must be unique on the LAN; the election address is
+    let instance = format!("node-{}-{}", election_addr.ip(), election_addr.port())
+        .replace([':', '.'], "-");
+    let host_name = format!("{}.local.", instance);
+    let election = election_addr.to_string();
+    let http_port_txt = http_port.to_string();
+    let properties = [
+        ("election_addr", election.as_str()),
+        ("http_port", http_port_txt.as_str()),
+    ];
+
+    let service = ServiceInfo::new(
+        SERVICE_TYPE,
+        &instance,
+        &host_name,
+        election_addr.ip(),
+        http_port,
+        &properties[..],
+    )
+    .context("build mDNS service record")?;
+
+    daemon.register(service).context("register mDNS service")?;
+    info!(
+        "Advertising {} via mDNS as {} (HTTP port {})",
+        SERVICE_TYPE, instance, http_port
+    );
+    Ok(daemon)
+}
@@ -0,0 +1,264 @@
+//! HTTP middleware
+//!
+//! Follower redirect for write requests: `node_state.leader` holds the leader's
+//! election address, which clients can't use for HTTP. With `peer_http_urls` in
+//! config.toml mapping each election address to its HTTP base URL, a follower
+//! answers POST/PUT/DELETE with `307 Temporary Redirect` to the same path on the
+//! leader, so clients no longer have to translate the 403 message themselves.
+//!
+//! Idempotency keys: a request carrying `Idempotency-Key` that succeeded is
+//! remembered for a while, and a retry with the same key gets the original
+//! response replayed instead of running again (e.g. "user exists" after a
+//! register whose response was lost). A key is bound to the request body it
+//! was first used with; reusing it for a different body is a 422.
+
+use crate::api::AppState;
+use axum::{
+    body::{to_bytes, Body},
+    extract::{Request, State},
+    http::{header, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Json, Response},
+};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::RwLock;
+use tracing::{info, warn};
+
+pub const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+
+/// How long a successful response is replayed for its key
+const IDEMPOTENCY_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Largest response body that is stored for replay
+const MAX_REPLAY_BODY_BYTES: usize = 64 * 1024;
+
+/// How long a key stays "in progress" before a retry may take it over, in case
+/// the first request's task never got to clean up
+const IN_PROGRESS_TTL: Duration = Duration::from_secs(60);
+
+/// Largest request body accepted with an `Idempotency-Key` (it is hashed up front)
+const MAX_IDEMPOTENT_REQUEST_BYTES: usize = 64 * 1024;
+
+#[derive(Debug, Clone)]
+pub enum IdempotencyEntry {
+    /// The first request with this key hasn't finished yet
+    InProgress {
+        body_hash: [u8; 32],
+        started_at: Instant,
+    },
+    Done {
+        body_hash: [u8; 32],
+        status: StatusCode,
+        content_type: Option<HeaderValue>,
+        body: Vec<u8>,
+        stored_at: Instant,
+    },
+}
+
+impl IdempotencyEntry {
+    fn body_hash(&self) -> &[u8; 32] {
+        match self {
+            IdempotencyEntry::InProgress { body_hash, .. } | IdempotencyEntry::Done { body_hash, .. } => body_hash,
+        }
+    }
+
+    fn is_live(&self) -> bool {
+        match self {
+            IdempotencyEntry::InProgress { started_at, .. } => started_at.elapsed() < IN_PROGRESS_TTL,
+            IdempotencyEntry::Done { stored_at, .. } => stored_at.elapsed() < IDEMPOTENCY_TTL,
+        }
+    }
+}
+
+/// "{path} {key}" -> entry; keys are scoped to the endpoint they were used on
+pub type IdempotencyStore = Arc<RwLock<HashMap<String, IdempotencyEntry>>>;
+
+/// Clears this request's `InProgress` entry when dropped, so a handler future
+/// dropped mid-request (client disconnect) doesn't leave the key stuck. Once the
+/// entry is `Done` or removed, dropping it does nothing.
+struct InProgressGuard {
+    store: IdempotencyStore,
+    store_key: String,
+    started_at: Instant,
+}
+
+impl Drop for InProgressGuard {
+    fn drop(&mut self) {
+        let store = self.store.clone();
+        let store_key = std::mem::take(&mut self.store_key);
+        let started_at = self.started_at;
+        tokio::spawn(async move {
+            let mut keys = store.write().await;
+            if matches!(keys.get(&store_key), Some(IdempotencyEntry::InProgress { started_at: s, .. }) if *s == started_at) {
+                keys.remove(&store_key);
+            }
+        });
+    }
+}
+
+pub async fn follower_redirect(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let is_mutating = matches!(
+        *request.method(),
+        Method::POST | Method::PUT | Method::DELETE
+    );
+    if !is_mutating {
+        return next.run(request).await;
+    }
+
+    let leader = {
+        let ns = state.node_state.read().await;
+        if ns.state == crate::State::Leader {
+            None
+        } else {
+            ns.leader.clone()
+        }
+    };
+
+    // Leader, or no known leader / no HTTP mapping: fall through to the handler's 403
+    let Some(leader_http) = leader.and_then(|leader| state.peer_http_urls.get(&leader).cloned())
+    else {
+        return next.run(request).await;
+    };
+
+    let path_and_query = request
+        .uri()
+        .path_and_query()
+        .map(|pq| pq.as_str())
+        .unwrap_or("/");
+    let location = format!("{}{}", leader_http.trim_end_matches('/'), path_and_query);
+
+    info!(
+        "Redirecting {} {} to leader at {}",
+        request.method(),
+        request.uri().path(),
+        location
+    );
+    (StatusCode::TEMPORARY_REDIRECT, [(header::LOCATION, location)]).into_response()
+}
+
+pub async fn idempotency(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(key) = request
+        .headers()
+        .get(IDEMPOTENCY_KEY_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(|k| !k.is_empty())
+    else {
+        return next.run(request).await;
+    };
+    let store_key = format!("{} {}", request.uri().path(), key);
+
+    // A key may only be replayed for the same request, so remember what was sent
+    let (parts, body) = request.into_parts();
+    let body = match to_bytes(body, MAX_IDEMPOTENT_REQUEST_BYTES).await {
+        Ok(body) => body,
+        Err(_) => {
+            return (
+                StatusCode::PAYLOAD_TOO_LARGE,
+                Json(serde_json::json!({
+                    "message": "Request body too large for an Idempotency-Key request"
+                })),
+            )
+                .into_response();
+        }
+    };
+    let body_hash: [u8; 32] = Sha256::digest(&body).into();
+    let request = Request::from_parts(parts, Body::from(body));
+
+    let started_at = Instant::now();
+    {
+        let mut keys = state.idempotency_keys.write().await;
+        keys.retain(|_, entry| entry.is_live());
+
+        if let Some(entry) = keys.get(&store_key) {
+            if entry.body_hash() != &body_hash {
+                return (
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    Json(serde_json::json!({
+                        "message": "This Idempotency-Key was already used with a different request body"
+                    })),
+                )
+                    .into_response();
+            }
+        }
+
+        match keys.get(&store_key) {
+            Some(IdempotencyEntry::Done {
+                status,
+                content_type,
+                body,
+                ..
+            }) => {
+                info!("Replaying response for {}", store_key);
+                let mut response = (*status, body.clone()).into_response();
+                if let Some(content_type) = content_type {
+                    response
+                        .headers_mut()
+                        .insert(header::CONTENT_TYPE, content_type.clone());
+                }
+                response
+                    .headers_mut()
+                    .insert("Idempotent-Replayed", HeaderValue::from_static("true"));
+                return response;
+            }
+            Some(IdempotencyEntry::InProgress { .. }) => {
+                return (
+                    StatusCode::CONFLICT,
+                    Json(serde_json::json!({
+                        "message": "A request with this Idempotency-Key is still in progress"
+                    })),
+                )
+                    .into_response();
+            }
+            None => {
+                keys.insert(store_key.clone(), IdempotencyEntry::InProgress { body_hash, started_at });
+            }
+        }
+    }
+
+    let _guard = InProgressGuard {
+        store: state.idempotency_keys.clone(),
+        store_key: store_key.clone(),
+        started_at,
+    };
+    let response = next.run(request).await;
+
+    // Only successes are replayed; a failed attempt can simply be retried
+    if !response.status().is_success() {
+        state.idempotency_keys.write().await.remove(&store_key);
+        return response;
+    }
+
+    let (parts, body) = response.into_parts();
+    let body = match to_bytes(body, MAX_REPLAY_BODY_BYTES).await {
+        Ok(body) => body,
+        Err(e) => {
+            warn!("Not storing response for {}: {}", store_key, e);
+            state.idempotency_keys.write().await.remove(&store_key);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to read response body").into_response();
+        }
+    };
+
+    state.idempotency_keys.write().await.insert(
+        store_key,
+        IdempotencyEntry::Done {
+            body_hash,
+            status: parts.status,
+            content_type: parts.headers.get(header::CONTENT_TYPE).cloned(),
+            body: body.to_vec(),
+            stored_at: Instant::now(),
+        },
+    );
+
+    Response::from_parts(parts, Body::from(body))
+}
@@ -1,5 +1,6 @@
 //! Configuration for Firebase Storage user registration
 
+use crate::registration::quota_manager::QuotaConfig;
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
 
@@ -11,6 +12,9 @@ pub struct RegistrationConfig {
     pub bucket_name: String,
     /// Folder/prefix for user files in the bucket
     pub users_folder_prefix: String,
+    /// Per-user image count and storage limits
+    #[serde(default)]
+    pub quota: QuotaConfig,
 }
 
 impl RegistrationConfig {
@@ -23,8 +27,14 @@ impl RegistrationConfig {
             credentials_path: credentials_path.into(),
             bucket_name: bucket_name.into(),
             users_folder_prefix: users_folder_prefix.into(),
+            quota: QuotaConfig::default(),
         }
     }
+
+    pub fn with_quota(mut self, quota: QuotaConfig) -> Self {
+        self.quota = quota;
+        self
+    }
 }
 
 impl Default for RegistrationConfig {
@@ -33,6 +43,7 @@ impl Default for RegistrationConfig {
             credentials_path: PathBuf::from("credentials/firebase-storage.json"),
             bucket_name: "your-project.appspot.com".to_string(),
             users_folder_prefix: "registered-users".to_string(),
+            quota: QuotaConfig::default(),
         }
     }
 }
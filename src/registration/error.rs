@@ -4,9 +4,6 @@ use thiserror::Error;
 
 #[derive(Error, Debug)]
 pub enum RegistrationError {
-    #[error("Authentication failed: {0}")]
-    AuthError(String),
-
     #[error("Failed to read credentials file: {0}")]
     CredentialsFileError(#[from] std::io::Error),
 
@@ -22,9 +19,35 @@ pub enum RegistrationError {
     #[error("User already exists: {0}")]
     UserAlreadyExists(String),
 
-    #[error("Configuration error: {0}")]
-    ConfigError(String),
-    
     #[error("Validation error: {0}")]
     ValidationError(String),
+
+    #[error("Firebase quota exceeded, retry after {retry_after_secs}s")]
+    QuotaExceeded { retry_after_secs: u64 },
+}
+
+/// Some deletions of a bulk delete failed; the rest went through
+#[derive(Error, Debug)]
+#[error("Deleted {deleted} files, {} failed", failed.len())]
+pub struct BulkDeleteError {
+    pub deleted: usize,
+    /// (filename, why it couldn't be deleted)
+    pub failed: Vec<(String, RegistrationError)>,
+}
+
+impl From<BulkDeleteError> for RegistrationError {
+    fn from(e: BulkDeleteError) -> Self {
+        // A quota hit is the likely cause of the rest too; keep it so callers answer 503
+        let quota = e.failed.iter().find_map(|(_, err)| match err {
+            RegistrationError::QuotaExceeded { retry_after_secs } => Some(*retry_after_secs),
+            _ => None,
+        });
+        match quota {
+            Some(retry_after_secs) => RegistrationError::QuotaExceeded { retry_after_secs },
+            None => RegistrationError::FirebaseApiError(e.to_string()),
+        }
+    }
 }
+
+/// Back-off suggested to clients when Firebase rate-limits us without a Retry-After value
+pub const DEFAULT_RETRY_AFTER_SECS: u64 = 60;
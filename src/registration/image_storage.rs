@@ -1,15 +1,64 @@
 //! Image storage for user images
 //! Structure: users/{username}/images/{timestamp}-{uuid}.{ext}
+//! Optional thumbnails: users/{username}/thumbnails/{same filename}
+//! Dedup markers: users/{username}/hashes/{sha256 hex}, content = image filename
 
-use crate::registration::error::RegistrationError;
+use crate::registration::error::{BulkDeleteError, RegistrationError};
+use crate::registration::object_store::ObjectStoreError;
+use crate::registration::quota_manager::UsageStats;
 use crate::registration::user_directory::UserDirectory;
-use cloud_storage::Client;
-use futures::stream::StreamExt;
-use image::{DynamicImage, ImageFormat};
-use std::io::Cursor;
-use tracing::info;
+use chrono::{DateTime, Utc};
+use futures::future::join_all;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use image::ImageFormat;
+use tracing::{info, warn};
 use uuid::Uuid;
 
+/// Content type for a stored image, based on its extension
+pub fn content_type_for(filename: &str) -> &'static str {
+    match filename.rsplit('.').next() {
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("webp") => "image/webp",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Default for `QuotaConfig::max_images_per_user`
+pub const MAX_SAMPLE_IMAGES: usize = 10;
+
+/// Maximum encoded size of a single image (a 128x128 RGBA PNG is well under this)
+pub const MAX_IMAGE_BYTES: usize = 128 * 1024;
+
+/// Where an uploaded image ended up
+#[derive(Debug, Clone, Serialize)]
+pub struct UploadResult {
+    pub filename: String,
+    /// The user already had an image with the same bytes; nothing was uploaded
+    pub was_duplicate: bool,
+}
+
+/// Check an image's encoded size, that it decodes, and that it is at most
+/// 128x128; `Err` describes the first problem found
+fn check_image(image_data: &[u8]) -> Result<(), String> {
+    // Bound the encoded size before decoding anything
+    if image_data.len() > MAX_IMAGE_BYTES {
+        return Err(format!(
+            "too large: {} bytes (max {} bytes)",
+            image_data.len(),
+            MAX_IMAGE_BYTES
+        ));
+    }
+
+    let img = image::load_from_memory(image_data).map_err(|e| format!("not a valid image: {}", e))?;
+    if img.width() > 128 || img.height() > 128 {
+        return Err(format!("too large: {}x{} (max 128x128)", img.width(), img.height()));
+    }
+    Ok(())
+}
+
 pub struct ImageStorage<'a> {
     user_directory: &'a UserDirectory,
 }
@@ -24,6 +73,37 @@ impl<'a> ImageStorage<'a> {
         format!("users/{}/images/", username)
     }
 
+    /// Get the thumbnails folder path for a user
+    fn get_thumbnails_folder(&self, username: &str) -> String {
+        format!("users/{}/thumbnails/", username)
+    }
+
+    /// Get the dedup marker path for an image's content
+    fn get_hash_path(&self, username: &str, image_data: &[u8]) -> String {
+        format!("users/{}/hashes/{}", username, hex::encode(Sha256::digest(image_data)))
+    }
+
+    /// Filename of the user's image with this content, if its marker exists
+    async fn find_duplicate(&self, hash_path: &str) -> Result<Option<String>, RegistrationError> {
+        match self.user_directory.store().download(hash_path).await {
+            Ok(content) => Ok(String::from_utf8(content).ok().filter(|f| !f.is_empty())),
+            Err(ObjectStoreError::NotFound(_)) => Ok(None),
+            Err(e) => Err(e.into_registration_error("Failed to check image hash")),
+        }
+    }
+
+    /// Whether `filename` is still stored in the user's images folder
+    async fn image_exists(&self, username: &str, filename: &str) -> Result<bool, RegistrationError> {
+        let path = format!("{}{}", self.get_images_folder(username), filename);
+        let objects = self
+            .user_directory
+            .store()
+            .list(&path)
+            .await
+            .map_err(|e| e.into_registration_error("Failed to check image"))?;
+        Ok(objects.iter().any(|obj| obj.name == path))
+    }
+
     /// Generate a unique image filename
     fn generate_filename(&self, extension: &str) -> String {
         let timestamp = chrono::Utc::now().timestamp();
@@ -31,29 +111,45 @@ impl<'a> ImageStorage<'a> {
         format!("{}-{}.{}", timestamp, uuid, extension)
     }
 
-    /// Upload an image for a user (must be registered and <= 128x128)
+    /// Upload an image for a user (must be registered and <= 128x128).
+    /// Uploading bytes the user already has returns the existing filename.
     pub async fn upload_image(
         &self,
         username: &str,
         image_data: Vec<u8>,
         format: ImageFormat,
-    ) -> Result<String, RegistrationError> {
+    ) -> Result<UploadResult, RegistrationError> {
         // 1. Verify user is registered
         self.user_directory.get_user(username).await?;
 
-        // 2. Validate image dimensions
-        let img = image::load_from_memory(&image_data)
-            .map_err(|e| RegistrationError::ValidationError(format!("Invalid image: {}", e)))?;
+        // 2-3. Validate size and dimensions
+        check_image(&image_data)
+            .map_err(|reason| RegistrationError::ValidationError(format!("Image is {}", reason)))?;
 
-        if img.width() > 128 || img.height() > 128 {
-            return Err(RegistrationError::ValidationError(format!(
-                "Image too large: {}x{} (max 128x128)",
-                img.width(),
-                img.height()
-            )));
+        // 4. Same bytes already stored: hand back that image instead of a copy
+        let hash_path = self.get_hash_path(username, &image_data);
+        if let Some(filename) = self.find_duplicate(&hash_path).await? {
+            if self.image_exists(username, &filename).await? {
+                info!("Duplicate image for user '{}', reusing {}", username, filename);
+                return Ok(UploadResult {
+                    filename,
+                    was_duplicate: true,
+                });
+            }
+            // The marker outlived its image (delete_image couldn't remove it)
+            warn!("Dropping stale hash marker '{}' for deleted image {}", hash_path, filename);
+            if let Err(e) = self.user_directory.store().delete(&hash_path).await {
+                warn!("Failed to delete hash marker '{}': {}", hash_path, e);
+            }
         }
 
-        // 3. Determine extension
+        // 5. Bound how many images a user keeps (every one is served by discovery)
+        let usage = self.fetch_usage(username).await?;
+        self.user_directory
+            .quota()
+            .check_upload(&usage, image_data.len() as u64)?;
+
+        // 6. Determine extension
         let extension = match format {
             ImageFormat::Png => "png",
             ImageFormat::Jpeg => "jpg",
@@ -61,7 +157,7 @@ impl<'a> ImageStorage<'a> {
             _ => return Err(RegistrationError::ValidationError("Unsupported format".to_string())),
         };
 
-        // 4. Generate path and upload
+        // 7. Generate path and upload
         let filename = self.generate_filename(extension);
         let full_path = format!("{}{}", self.get_images_folder(username), filename);
 
@@ -73,21 +169,77 @@ impl<'a> ImageStorage<'a> {
         };
 
         self.user_directory
-            .get_client()
-            .object()
-            .create(
-                self.user_directory.get_bucket_name(),
-                image_data,
-                &full_path,
-                mime_type,
-            )
-            .await
-            .map_err(|e| {
-                RegistrationError::FirebaseApiError(format!("Failed to upload image: {}", e))
-            })?;
+            .store()
+            .create(&full_path, image_data, mime_type)
+            .await
+            .map_err(|e| e.into_registration_error("Failed to upload image"))?;
+
+        // 8. Record the hash; without the marker a re-upload just stores a copy
+        if let Err(e) = self
+            .user_directory
+            .store()
+            .create(&hash_path, filename.clone().into_bytes(), "text/plain")
+            .await
+        {
+            warn!("Failed to write hash marker for '{}': {}", full_path, e);
+        }
 
         info!("Uploaded image for user '{}': {}", username, full_path);
-        Ok(filename)
+        Ok(UploadResult {
+            filename,
+            was_duplicate: false,
+        })
+    }
+
+    /// Add to or replace a user's sample images.
+    ///
+    /// Every image is validated before anything is changed, so a bad entry
+    /// leaves the existing images untouched. With `append`, the new images are
+    /// added as long as the total stays within the user quota; otherwise the
+    /// existing images are deleted and replaced.
+    pub async fn update_sample_images(
+        &self,
+        username: &str,
+        images: Vec<Vec<u8>>,
+        append: bool,
+    ) -> Result<Vec<String>, RegistrationError> {
+        let existing = self.list_images(username).await?;
+
+        let mut validated = Vec::with_capacity(images.len());
+        for (index, data) in images.into_iter().enumerate() {
+            let format = image::guess_format(&data).map_err(|e| {
+                RegistrationError::ValidationError(format!("Image {} is not a valid image: {}", index, e))
+            })?;
+            check_image(&data).map_err(|reason| {
+                RegistrationError::ValidationError(format!("Image {} is {}", index, reason))
+            })?;
+            validated.push((data, format));
+        }
+
+        let usage = if append { self.fetch_usage(username).await? } else { UsageStats::default() };
+        let new_bytes = validated.iter().map(|(data, _)| data.len() as u64).sum();
+        self.user_directory
+            .quota()
+            .check_batch(&usage, validated.len(), new_bytes)?;
+
+        if !append {
+            for filename in &existing {
+                self.delete_image(username, filename).await?;
+            }
+        }
+
+        let mut filenames = Vec::with_capacity(validated.len());
+        for (data, format) in validated {
+            filenames.push(self.upload_image(username, data, format).await?.filename);
+        }
+
+        info!(
+            "Updated sample images for user '{}': {} uploaded (append={})",
+            username,
+            filenames.len(),
+            append
+        );
+        Ok(filenames)
     }
 
     /// List all images for a user
@@ -95,43 +247,130 @@ impl<'a> ImageStorage<'a> {
         // Verify user exists
         self.user_directory.get_user(username).await?;
 
+        self.fetch_image_list(username).await
+    }
+
+    /// List one page of a user's images, using the backend's own pagination.
+    ///
+    /// `name_prefix` narrows the listing server-side. `updated_since` can only be
+    /// applied after fetching, so backend pages are fetched until `page_size`
+    /// images match or the listing ends; a page is only short when the token is `None`.
+    pub async fn list_images_paginated(
+        &self,
+        username: &str,
+        name_prefix: Option<&str>,
+        updated_since: Option<DateTime<Utc>>,
+        mut page_token: Option<String>,
+        page_size: usize,
+    ) -> Result<(Vec<String>, Option<String>), RegistrationError> {
+        // Verify user exists
+        self.user_directory.get_user(username).await?;
+
         let images_prefix = self.get_images_folder(username);
-        
-        let stream = self
+        let list_prefix = format!("{}{}", images_prefix, name_prefix.unwrap_or(""));
+        let mut images = Vec::new();
+        loop {
+            // Only ask for what's still missing, so the token never skips unreturned images
+            let (objects, next_page_token) = self
+                .user_directory
+                .store()
+                .list_page(&list_prefix, page_token, page_size - images.len())
+                .await
+                .map_err(|e| e.into_registration_error("Failed to list images"))?;
+
+            images.extend(
+                objects
+                    .into_iter()
+                    .filter(|obj| updated_since.is_none_or(|since| obj.updated >= since))
+                    .filter_map(|obj| obj.name.strip_prefix(&images_prefix).map(str::to_string)),
+            );
+            page_token = next_page_token;
+
+            if images.len() >= page_size || page_token.is_none() {
+                return Ok((images, page_token));
+            }
+        }
+    }
+
+    /// Image count and total stored bytes for a user
+    pub async fn get_usage(&self, username: &str) -> Result<UsageStats, RegistrationError> {
+        // Verify user exists
+        self.user_directory.get_user(username).await?;
+
+        self.fetch_usage(username).await
+    }
+
+    /// Sum the user's images folder without verifying the user
+    async fn fetch_usage(&self, username: &str) -> Result<UsageStats, RegistrationError> {
+        let objects = self
             .user_directory
-            .get_client()
-            .object()
-            .list(self.user_directory.get_bucket_name(), Default::default())
+            .store()
+            .list(&self.get_images_folder(username))
             .await
-            .map_err(|e| {
-                RegistrationError::FirebaseApiError(format!("Failed to list images: {}", e))
-            })?;
+            .map_err(|e| e.into_registration_error("Failed to list images"))?;
 
-        tokio::pin!(stream);
+        Ok(UsageStats {
+            image_count: objects.len(),
+            total_bytes: objects.iter().map(|obj| obj.size).sum(),
+        })
+    }
 
-        let mut images = Vec::new();
+    /// List images for several users concurrently.
+    ///
+    /// Registration is not re-checked here; callers are expected to have
+    /// verified the users already (e.g. via `UserDirectory::batch_get_users`).
+    /// Users whose listing fails are logged and left out of the result.
+    pub async fn batch_list_images(&self, usernames: &[String]) -> HashMap<String, Vec<String>> {
+        let results = join_all(usernames.iter().map(|username| self.fetch_image_list(username))).await;
 
-        while let Some(result) = stream.next().await {
+        let mut images = HashMap::new();
+        for (username, result) in usernames.iter().zip(results) {
             match result {
-                Ok(object_list) => {
-                    for obj in object_list.items {
-                        if obj.name.starts_with(&images_prefix) {
-                            // Extract just the filename
-                            if let Some(filename) = obj.name.strip_prefix(&images_prefix) {
-                                images.push(filename.to_string());
-                            }
-                        }
-                    }
+                Ok(filenames) => {
+                    images.insert(username.clone(), filenames);
                 }
                 Err(e) => {
-                    return Err(RegistrationError::FirebaseApiError(format!(
-                        "Error listing images: {}",
-                        e
-                    )));
+                    warn!("Failed to list images for user '{}': {}", username, e);
                 }
             }
         }
 
+        images
+    }
+
+    /// Download several `(username, filename)` images concurrently.
+    ///
+    /// Like `batch_list_images`, this skips the per-image registration check.
+    pub async fn batch_download_images(
+        &self,
+        items: &[(String, String)],
+    ) -> Vec<Result<Vec<u8>, RegistrationError>> {
+        join_all(
+            items
+                .iter()
+                .map(|(username, filename)| self.fetch_image(username, filename)),
+        )
+        .await
+    }
+
+    /// List image filenames under a user's images folder without verifying the user
+    async fn fetch_image_list(&self, username: &str) -> Result<Vec<String>, RegistrationError> {
+        let images_prefix = self.get_images_folder(username);
+        
+        let objects = self
+            .user_directory
+            .store()
+            .list(&images_prefix)
+            .await
+            .map_err(|e| e.into_registration_error("Failed to list images"))?;
+
+        // Extract just the filenames
+        let images = objects
+            .iter()
+            .filter_map(|obj| obj.name.strip_prefix(&images_prefix))
+            .map(|filename| filename.to_string())
+            .collect();
+
         Ok(images)
     }
 
@@ -144,20 +383,40 @@ impl<'a> ImageStorage<'a> {
         // Verify user exists
         self.user_directory.get_user(username).await?;
 
+        self.fetch_image(username, filename).await
+    }
+
+    /// Download an image's thumbnail, or the full image if it has none
+    pub async fn download_thumbnail(
+        &self,
+        username: &str,
+        filename: &str,
+    ) -> Result<Vec<u8>, RegistrationError> {
+        // Verify user exists
+        self.user_directory.get_user(username).await?;
+
+        let thumbnail_path = format!("{}{}", self.get_thumbnails_folder(username), filename);
+        match self.user_directory.store().download(&thumbnail_path).await {
+            Ok(data) => Ok(data),
+            Err(ObjectStoreError::NotFound(_)) => self.fetch_image(username, filename).await,
+            Err(e) => Err(e.into_registration_error("Failed to download thumbnail")),
+        }
+    }
+
+    /// Download an image without verifying the user
+    async fn fetch_image(&self, username: &str, filename: &str) -> Result<Vec<u8>, RegistrationError> {
         let full_path = format!("{}{}", self.get_images_folder(username), filename);
 
         let data = self
             .user_directory
-            .get_client()
-            .object()
-            .download(self.user_directory.get_bucket_name(), &full_path)
+            .store()
+            .download(&full_path)
             .await
-            .map_err(|e| {
-                if e.to_string().contains("404") {
+            .map_err(|e| match e {
+                ObjectStoreError::NotFound(_) => {
                     RegistrationError::ValidationError(format!("Image not found: {}", filename))
-                } else {
-                    RegistrationError::FirebaseApiError(format!("Failed to download image: {}", e))
                 }
+                e => e.into_registration_error("Failed to download image"),
             })?;
 
         Ok(data)
@@ -171,16 +430,312 @@ impl<'a> ImageStorage<'a> {
     ) -> Result<(), RegistrationError> {
         let full_path = format!("{}{}", self.get_images_folder(username), filename);
 
+        // The marker is keyed by content, so read the image before it goes
+        let hash_path = match self.user_directory.store().download(&full_path).await {
+            Ok(data) => Some(self.get_hash_path(username, &data)),
+            Err(ObjectStoreError::NotFound(_)) => None,
+            Err(e) => return Err(e.into_registration_error("Failed to delete image")),
+        };
+
         self.user_directory
-            .get_client()
-            .object()
-            .delete(self.user_directory.get_bucket_name(), &full_path)
+            .store()
+            .delete(&full_path)
             .await
-            .map_err(|e| {
-                RegistrationError::FirebaseApiError(format!("Failed to delete image: {}", e))
-            })?;
+            .map_err(|e| e.into_registration_error("Failed to delete image"))?;
+
+        // Only drop the marker if it still points at this image
+        if let Some(hash_path) = hash_path {
+            if self.find_duplicate(&hash_path).await.ok().flatten().as_deref() == Some(filename) {
+                if let Err(e) = self.user_directory.store().delete(&hash_path).await {
+                    warn!("Failed to delete hash marker '{}': {}", hash_path, e);
+                }
+            }
+        }
 
         info!("Deleted image for user '{}': {}", username, filename);
         Ok(())
     }
+
+    /// Delete every image a user has. Keeps going past failures and reports
+    /// them all in `BulkDeleteError`; returns the number deleted otherwise.
+    pub async fn delete_all_images(&self, username: &str) -> Result<usize, BulkDeleteError> {
+        let filenames = self.list_images(username).await.map_err(|e| BulkDeleteError {
+            deleted: 0,
+            failed: vec![(self.get_images_folder(username), e)],
+        })?;
+
+        let results = join_all(
+            filenames
+                .iter()
+                .map(|filename| self.delete_image(username, filename)),
+        )
+        .await;
+
+        collect_bulk_delete(filenames, results)
+    }
+
+    /// Delete every thumbnail a user has, like `delete_all_images`
+    pub async fn delete_all_thumbnails(&self, username: &str) -> Result<usize, BulkDeleteError> {
+        let prefix = self.get_thumbnails_folder(username);
+        let objects = self
+            .user_directory
+            .store()
+            .list(&prefix)
+            .await
+            .map_err(|e| BulkDeleteError {
+                deleted: 0,
+                failed: vec![(prefix.clone(), e.into_registration_error("Failed to list thumbnails"))],
+            })?;
+
+        let filenames: Vec<String> = objects
+            .iter()
+            .filter_map(|obj| obj.name.strip_prefix(&prefix))
+            .map(|filename| filename.to_string())
+            .collect();
+
+        let prefix = &prefix;
+        let results = join_all(filenames.iter().map(|filename| async move {
+            self.user_directory
+                .store()
+                .delete(&format!("{}{}", prefix, filename))
+                .await
+                .map_err(|e| e.into_registration_error("Failed to delete thumbnail"))
+        }))
+        .await;
+
+        let deleted = collect_bulk_delete(filenames, results)?;
+        if deleted > 0 {
+            info!("Deleted {} thumbnails for user '{}'", deleted, username);
+        }
+        Ok(deleted)
+    }
+}
+
+/// Count successful deletions, or gather every failure with its filename
+fn collect_bulk_delete(
+    filenames: Vec<String>,
+    results: Vec<Result<(), RegistrationError>>,
+) -> Result<usize, BulkDeleteError> {
+    let mut deleted = 0;
+    let mut failed = Vec::new();
+    for (filename, result) in filenames.into_iter().zip(results) {
+        match result {
+            Ok(()) => deleted += 1,
+            Err(e) => failed.push((filename, e)),
+        }
+    }
+
+    if failed.is_empty() {
+        Ok(deleted)
+    } else {
+        Err(BulkDeleteError { deleted, failed })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::registration::quota_manager::{self, QuotaConfig};
+    use crate::registration::test_util::{directory, png, register};
+    use crate::registration::RegistrationConfig;
+
+    fn directory_with_max_images(max_images_per_user: usize) -> UserDirectory {
+        UserDirectory::new_in_memory(RegistrationConfig::default().with_quota(QuotaConfig {
+            max_images_per_user,
+            ..QuotaConfig::default()
+        }))
+    }
+
+    #[tokio::test]
+    async fn delete_all_images_removes_every_image() {
+        let dir = directory();
+        register(&dir, "alice").await;
+        let storage = ImageStorage::new(&dir);
+        for seed in 0..5 {
+            storage
+                .upload_image("alice", png(seed, 16), ImageFormat::Png)
+                .await
+                .unwrap();
+        }
+        assert_eq!(storage.list_images("alice").await.unwrap().len(), 5);
+
+        assert_eq!(storage.delete_all_images("alice").await.unwrap(), 5);
+        assert!(storage.list_images("alice").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn upload_limit_comes_from_quota() {
+        let dir = directory_with_max_images(MAX_SAMPLE_IMAGES + 2);
+        register(&dir, "alice").await;
+        let storage = ImageStorage::new(&dir);
+        for seed in 0..(MAX_SAMPLE_IMAGES + 2) as u8 {
+            storage
+                .upload_image("alice", png(seed, 16), ImageFormat::Png)
+                .await
+                .unwrap();
+        }
+
+        let err = storage
+            .upload_image("alice", png(200, 16), ImageFormat::Png)
+            .await
+            .unwrap_err();
+        assert!(quota_manager::is_quota_exceeded(&err));
+    }
+
+    #[tokio::test]
+    async fn update_sample_images_checks_quota() {
+        let dir = directory_with_max_images(2);
+        register(&dir, "alice").await;
+        let storage = ImageStorage::new(&dir);
+        storage
+            .update_sample_images("alice", vec![png(1, 16), png(2, 16)], false)
+            .await
+            .unwrap();
+
+        let err = storage
+            .update_sample_images("alice", vec![png(3, 16)], true)
+            .await
+            .unwrap_err();
+        assert!(quota_manager::is_quota_exceeded(&err));
+        assert_eq!(storage.list_images("alice").await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn duplicate_upload_reuses_stored_image() {
+        let dir = directory();
+        register(&dir, "alice").await;
+        let storage = ImageStorage::new(&dir);
+
+        let first = storage
+            .upload_image("alice", png(7, 16), ImageFormat::Png)
+            .await
+            .unwrap();
+        let second = storage
+            .upload_image("alice", png(7, 16), ImageFormat::Png)
+            .await
+            .unwrap();
+
+        assert!(!first.was_duplicate);
+        assert!(second.was_duplicate);
+        assert_eq!(second.filename, first.filename);
+        assert_eq!(storage.list_images("alice").await.unwrap(), vec![first.filename]);
+    }
+
+    #[tokio::test]
+    async fn oversized_sample_image_is_rejected_by_index() {
+        let dir = directory();
+        register(&dir, "alice").await;
+        let storage = ImageStorage::new(&dir);
+        storage
+            .update_sample_images("alice", vec![png(1, 16)], false)
+            .await
+            .unwrap();
+
+        let err = storage
+            .update_sample_images("alice", vec![png(2, 16), png(3, 200)], false)
+            .await
+            .unwrap_err();
+        let RegistrationError::ValidationError(message) = err else {
+            panic!("expected a validation error, got {:?}", err);
+        };
+        assert!(message.starts_with("Image 1 is too large"), "{}", message);
+        // nothing was replaced
+        assert_eq!(storage.list_images("alice").await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn list_images_paginated_pages_through_all_images() {
+        let dir = directory_with_max_images(25);
+        register(&dir, "alice").await;
+        let storage = ImageStorage::new(&dir);
+        for seed in 0..25 {
+            storage
+                .upload_image("alice", png(seed, 16), ImageFormat::Png)
+                .await
+                .unwrap();
+        }
+
+        let (first, token) = storage
+            .list_images_paginated("alice", None, None, None, 20)
+            .await
+            .unwrap();
+        assert_eq!(first.len(), 20);
+        let token = token.expect("more images follow");
+
+        let (second, token) = storage
+            .list_images_paginated("alice", None, None, Some(token), 20)
+            .await
+            .unwrap();
+        assert_eq!(second.len(), 5);
+        assert!(token.is_none());
+
+        let mut all: Vec<String> = first.into_iter().chain(second).collect();
+        all.sort();
+        all.dedup();
+        let mut expected = storage.list_images("alice").await.unwrap();
+        expected.sort();
+        assert_eq!(all, expected);
+    }
+
+    #[tokio::test]
+    async fn updated_since_filter_never_returns_short_page_with_token() {
+        let dir = directory_with_max_images(25);
+        register(&dir, "alice").await;
+        let storage = ImageStorage::new(&dir);
+        for seed in 0..25 {
+            storage
+                .upload_image("alice", png(seed, 16), ImageFormat::Png)
+                .await
+                .unwrap();
+        }
+
+        // Nothing matches, so every backend page is drained into one empty final page
+        let future = Utc::now() + chrono::Duration::hours(1);
+        let (images, token) = storage
+            .list_images_paginated("alice", None, Some(future), None, 10)
+            .await
+            .unwrap();
+        assert!(images.is_empty());
+        assert!(token.is_none());
+
+        let past = Utc::now() - chrono::Duration::hours(1);
+        let (images, token) = storage
+            .list_images_paginated("alice", None, Some(past), None, 10)
+            .await
+            .unwrap();
+        assert_eq!(images.len(), 10);
+        assert!(token.is_some());
+    }
+
+    #[tokio::test]
+    async fn stale_hash_marker_does_not_block_reupload() {
+        let dir = directory();
+        register(&dir, "alice").await;
+        let storage = ImageStorage::new(&dir);
+        let first = storage
+            .upload_image("alice", png(1, 16), ImageFormat::Png)
+            .await
+            .unwrap();
+
+        // The image goes but its marker stays, as when delete_image fails to remove it
+        dir.store()
+            .delete(&format!("users/alice/images/{}", first.filename))
+            .await
+            .unwrap();
+
+        let again = storage
+            .upload_image("alice", png(1, 16), ImageFormat::Png)
+            .await
+            .unwrap();
+        assert!(!again.was_duplicate);
+        assert_ne!(again.filename, first.filename);
+        assert_eq!(storage.list_images("alice").await.unwrap(), vec![again.filename.clone()]);
+
+        let third = storage
+            .upload_image("alice", png(1, 16), ImageFormat::Png)
+            .await
+            .unwrap();
+        assert!(third.was_duplicate);
+        assert_eq!(third.filename, again.filename);
+    }
 }
@@ -5,13 +5,17 @@ pub mod config;
 pub mod error;
 pub mod image_storage;
 pub mod note_storage;  // NEW
+pub mod object_store;
+pub mod quota_manager;
+#[cfg(test)]
+pub(crate) mod test_util;
 pub mod user_directory;
 pub mod user_info;
 
-pub use auth::FirebaseAuth;
 pub use config::RegistrationConfig;
 pub use error::RegistrationError;
 pub use image_storage::ImageStorage;
 pub use note_storage::{ImageNote, NoteStorage};  // NEW
+pub use quota_manager::QuotaConfig;
 pub use user_directory::UserDirectory;
-pub use user_info::{UserInfo, UserStatus};
+pub use user_info::{canonical_addr, UserInfo, UserStatus};
@@ -2,8 +2,8 @@
 //! Structure: users/{username}/notes/{image_filename}.json
 
 use crate::registration::error::RegistrationError;
+use crate::registration::object_store::ObjectStoreError;
 use crate::registration::user_directory::UserDirectory;
-use futures::stream::StreamExt;
 use serde::{Deserialize, Serialize};
 use tracing::info;
 
@@ -45,26 +45,16 @@ impl<'a> NoteStorage<'a> {
         // 2. Verify target image exists
         let image_path = format!("users/{}/images/{}", target_username, target_image);
         
-        match self
-            .user_directory
-            .get_client()
-            .object()
-            .download(self.user_directory.get_bucket_name(), &image_path)
-            .await
-        {
+        match self.user_directory.store().download(&image_path).await {
             Ok(_) => {} // Image exists
+            Err(ObjectStoreError::NotFound(_)) => {
+                return Err(RegistrationError::ValidationError(format!(
+                    "Image not found: {}",
+                    target_image
+                )));
+            }
             Err(e) => {
-                if e.to_string().contains("404") || e.to_string().contains("No such object") {
-                    return Err(RegistrationError::ValidationError(format!(
-                        "Image not found: {}",
-                        target_image
-                    )));
-                } else {
-                    return Err(RegistrationError::FirebaseApiError(format!(
-                        "Error checking image: {}",
-                        e
-                    )));
-                }
+                return Err(e.into_registration_error("Error checking image"));
             }
         }
 
@@ -80,18 +70,10 @@ impl<'a> NoteStorage<'a> {
         let note_path = self.get_note_path(target_username, target_image);
 
         self.user_directory
-            .get_client()
-            .object()
-            .create(
-                self.user_directory.get_bucket_name(),
-                note_json.as_bytes().to_vec(),
-                &note_path,
-                "application/json",
-            )
+            .store()
+            .create(&note_path, note_json.as_bytes().to_vec(), "application/json")
             .await
-            .map_err(|e| {
-                RegistrationError::FirebaseApiError(format!("Failed to add note: {}", e))
-            })?;
+            .map_err(|e| e.into_registration_error("Failed to add note"))?;
 
         info!(
             "Added note for {}/{}: view_count_edit={}",
@@ -108,40 +90,23 @@ impl<'a> NoteStorage<'a> {
 
         let notes_prefix = self.get_notes_folder(username);
 
-        let stream = self
+        let objects = self
             .user_directory
-            .get_client()
-            .object()
-            .list(self.user_directory.get_bucket_name(), Default::default())
+            .store()
+            .list(&notes_prefix)
             .await
-            .map_err(|e| {
-                RegistrationError::FirebaseApiError(format!("Failed to list notes: {}", e))
-            })?;
-
-        tokio::pin!(stream);
+            .map_err(|e| e.into_registration_error("Failed to list notes"))?;
 
         let mut notes = Vec::new();
 
-        while let Some(result) = stream.next().await {
-            match result {
-                Ok(object_list) => {
-                    for obj in object_list.items {
-                        if obj.name.starts_with(&notes_prefix) && obj.name.ends_with(".json") {
-                            match self.download_note(&obj.name).await {
-                                Ok(note) => notes.push(note),
-                                Err(e) => {
-                                    tracing::warn!("Failed to read note {}: {}", obj.name, e);
-                                }
-                            }
-                        }
+        for obj in objects {
+            if obj.name.ends_with(".json") {
+                match self.download_note(&obj.name).await {
+                    Ok(note) => notes.push(note),
+                    Err(e) => {
+                        tracing::warn!("Failed to read note {}: {}", obj.name, e);
                     }
                 }
-                Err(e) => {
-                    return Err(RegistrationError::FirebaseApiError(format!(
-                        "Error listing notes: {}",
-                        e
-                    )));
-                }
             }
         }
 
@@ -152,13 +117,10 @@ impl<'a> NoteStorage<'a> {
     async fn download_note(&self, note_path: &str) -> Result<ImageNote, RegistrationError> {
         let data = self
             .user_directory
-            .get_client()
-            .object()
-            .download(self.user_directory.get_bucket_name(), note_path)
+            .store()
+            .download(note_path)
             .await
-            .map_err(|e| {
-                RegistrationError::FirebaseApiError(format!("Failed to download note: {}", e))
-            })?;
+            .map_err(|e| e.into_registration_error("Failed to download note"))?;
 
         let note: ImageNote = serde_json::from_slice(&data)?;
         Ok(note)
@@ -0,0 +1,355 @@
+//! Object storage backend shared by users, images and notes
+//! Firebase Storage in production, an in-memory bucket in test mode
+
+use crate::registration::error::{RegistrationError, DEFAULT_RETRY_AFTER_SECS};
+use chrono::{DateTime, Utc};
+use cloud_storage::{Client, ListRequest};
+use futures::stream::StreamExt;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use thiserror::Error;
+use tokio::sync::RwLock;
+
+#[derive(Error, Debug)]
+pub enum ObjectStoreError {
+    #[error("No such object: {0}")]
+    NotFound(String),
+
+    #[error("Rate limited, retry after {retry_after_secs}s")]
+    QuotaExceeded { retry_after_secs: u64 },
+
+    #[error("{0}")]
+    Api(String),
+}
+
+impl ObjectStoreError {
+    /// Convert to a `RegistrationError`, keeping quota hits distinct so the API can answer 503
+    pub fn into_registration_error(self, context: &str) -> RegistrationError {
+        match self {
+            ObjectStoreError::QuotaExceeded { retry_after_secs } => {
+                RegistrationError::QuotaExceeded { retry_after_secs }
+            }
+            e => RegistrationError::FirebaseApiError(format!("{}: {}", context, e)),
+        }
+    }
+}
+
+/// Listing entry for a stored object
+#[derive(Debug, Clone)]
+pub struct ObjectEntry {
+    pub name: String,
+    pub size: u64,
+    pub updated: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone)]
+struct StoredObject {
+    data: Vec<u8>,
+    updated: DateTime<Utc>,
+}
+
+/// In-memory bucket used in test mode: object path -> bytes, no network calls
+#[derive(Debug, Clone, Default)]
+pub struct InMemoryBucket {
+    objects: Arc<RwLock<BTreeMap<String, StoredObject>>>,
+    /// Simulated outage: every call fails while set
+    unavailable: Arc<AtomicBool>,
+}
+
+impl InMemoryBucket {
+    /// Make every call fail (or work again), to exercise storage outages
+    #[cfg(test)]
+    pub fn set_unavailable(&self, unavailable: bool) {
+        self.unavailable.store(unavailable, Ordering::Relaxed);
+    }
+}
+
+pub enum ObjectStore {
+    Firebase { client: Client, bucket: String },
+    InMemory(InMemoryBucket),
+}
+
+impl ObjectStore {
+    fn check_available(&self) -> Result<(), ObjectStoreError> {
+        match self {
+            ObjectStore::InMemory(mem) if mem.unavailable.load(Ordering::Relaxed) => {
+                Err(ObjectStoreError::Api("in-memory bucket is unavailable".to_string()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Create or overwrite an object
+    pub async fn create(
+        &self,
+        path: &str,
+        data: Vec<u8>,
+        mime_type: &str,
+    ) -> Result<(), ObjectStoreError> {
+        self.check_available()?;
+        match self {
+            ObjectStore::Firebase { client, bucket } => {
+                client
+                    .object()
+                    .create(bucket, data, path, mime_type)
+                    .await
+                    .map_err(firebase_error)?;
+                Ok(())
+            }
+            ObjectStore::InMemory(mem) => {
+                mem.objects.write().await.insert(
+                    path.to_string(),
+                    StoredObject {
+                        data,
+                        updated: Utc::now(),
+                    },
+                );
+                Ok(())
+            }
+        }
+    }
+
+    /// Download an object's bytes
+    pub async fn download(&self, path: &str) -> Result<Vec<u8>, ObjectStoreError> {
+        self.check_available()?;
+        match self {
+            ObjectStore::Firebase { client, bucket } => {
+                client.object().download(bucket, path).await.map_err(firebase_error)
+            }
+            ObjectStore::InMemory(mem) => mem
+                .objects
+                .read()
+                .await
+                .get(path)
+                .map(|obj| obj.data.clone())
+                .ok_or_else(|| ObjectStoreError::NotFound(path.to_string())),
+        }
+    }
+
+    /// Delete an object
+    pub async fn delete(&self, path: &str) -> Result<(), ObjectStoreError> {
+        self.check_available()?;
+        match self {
+            ObjectStore::Firebase { client, bucket } => {
+                client.object().delete(bucket, path).await.map_err(firebase_error)
+            }
+            ObjectStore::InMemory(mem) => mem
+                .objects
+                .write()
+                .await
+                .remove(path)
+                .map(|_| ())
+                .ok_or_else(|| ObjectStoreError::NotFound(path.to_string())),
+        }
+    }
+
+    /// List every object whose name starts with `prefix`
+    pub async fn list(&self, prefix: &str) -> Result<Vec<ObjectEntry>, ObjectStoreError> {
+        self.check_available()?;
+        match self {
+            ObjectStore::Firebase { client, bucket } => {
+                let request = ListRequest {
+                    prefix: Some(prefix.to_string()),
+                    ..Default::default()
+                };
+
+                let stream = client.object().list(bucket, request).await.map_err(firebase_error)?;
+
+                tokio::pin!(stream);
+
+                let mut entries = Vec::new();
+
+                while let Some(result) = stream.next().await {
+                    let object_list = result.map_err(firebase_error)?;
+                    for obj in object_list.items {
+                        // Prefix is applied server-side; keep the check as a guard
+                        if obj.name.starts_with(prefix) {
+                            entries.push(ObjectEntry {
+                                name: obj.name,
+                                size: obj.size,
+                                updated: obj.updated,
+                            });
+                        }
+                    }
+                }
+
+                Ok(entries)
+            }
+            ObjectStore::InMemory(mem) => Ok(mem
+                .objects
+                .read()
+                .await
+                .range(prefix.to_string()..)
+                .take_while(|(name, _)| name.starts_with(prefix))
+                .map(|(name, obj)| ObjectEntry {
+                    name: name.clone(),
+                    size: obj.data.len() as u64,
+                    updated: obj.updated,
+                })
+                .collect()),
+        }
+    }
+
+    /// List one page of objects under `prefix`, in name order.
+    ///
+    /// Returns at most `page_size` entries and a token for the next page, or
+    /// `None` when this was the last page.
+    pub async fn list_page(
+        &self,
+        prefix: &str,
+        page_token: Option<String>,
+        page_size: usize,
+    ) -> Result<(Vec<ObjectEntry>, Option<String>), ObjectStoreError> {
+        self.check_available()?;
+        match self {
+            ObjectStore::Firebase { client, bucket } => {
+                let request = ListRequest {
+                    prefix: Some(prefix.to_string()),
+                    max_results: Some(page_size),
+                    page_token,
+                    ..Default::default()
+                };
+
+                let stream = client.object().list(bucket, request).await.map_err(firebase_error)?;
+
+                tokio::pin!(stream);
+
+                // Only the first page is fetched, as in list_folders_page
+                match stream.next().await {
+                    Some(result) => {
+                        let object_list = result.map_err(firebase_error)?;
+                        let entries = object_list
+                            .items
+                            .into_iter()
+                            .filter(|obj| obj.name.starts_with(prefix))
+                            .map(|obj| ObjectEntry {
+                                name: obj.name,
+                                size: obj.size,
+                                updated: obj.updated,
+                            })
+                            .collect();
+                        Ok((entries, object_list.next_page_token))
+                    }
+                    None => Ok((Vec::new(), None)),
+                }
+            }
+            ObjectStore::InMemory(mem) => {
+                let objects = mem.objects.read().await;
+
+                // The token is the last name of the previous page
+                let mut entries: Vec<ObjectEntry> = objects
+                    .range(prefix.to_string()..)
+                    .take_while(|(name, _)| name.starts_with(prefix))
+                    .filter(|(name, _)| page_token.as_ref().is_none_or(|token| *name > token))
+                    .take(page_size + 1)
+                    .map(|(name, obj)| ObjectEntry {
+                        name: name.clone(),
+                        size: obj.data.len() as u64,
+                        updated: obj.updated,
+                    })
+                    .collect();
+
+                let next_page_token = if entries.len() > page_size {
+                    entries.truncate(page_size);
+                    entries.last().map(|entry| entry.name.clone())
+                } else {
+                    None
+                };
+
+                Ok((entries, next_page_token))
+            }
+        }
+    }
+
+    /// List one page of "folders" directly under `prefix` (names up to the next `/`).
+    ///
+    /// Returns the folder prefixes (e.g. `users/alice/`) and a token for the next
+    /// page, or `None` when this was the last page.
+    pub async fn list_folders_page(
+        &self,
+        prefix: &str,
+        page_token: Option<String>,
+        page_size: usize,
+    ) -> Result<(Vec<String>, Option<String>), ObjectStoreError> {
+        self.check_available()?;
+        match self {
+            ObjectStore::Firebase { client, bucket } => {
+                let request = ListRequest {
+                    prefix: Some(prefix.to_string()),
+                    delimiter: Some("/".to_string()),
+                    max_results: Some(page_size),
+                    page_token,
+                    ..Default::default()
+                };
+
+                let stream = client.object().list(bucket, request).await.map_err(firebase_error)?;
+
+                tokio::pin!(stream);
+
+                // Only the first page is fetched; the stream would follow next_page_token otherwise
+                match stream.next().await {
+                    Some(result) => {
+                        let object_list = result.map_err(firebase_error)?;
+                        Ok((object_list.prefixes, object_list.next_page_token))
+                    }
+                    None => Ok((Vec::new(), None)),
+                }
+            }
+            ObjectStore::InMemory(mem) => {
+                let objects = mem.objects.read().await;
+
+                let mut folders: Vec<String> = objects
+                    .range(prefix.to_string()..)
+                    .take_while(|(name, _)| name.starts_with(prefix))
+                    .filter_map(|(name, _)| {
+                        let rest = &name[prefix.len()..];
+                        rest.find('/').map(|i| format!("{}{}", prefix, &rest[..=i]))
+                    })
+                    .filter(|folder| page_token.as_ref().is_none_or(|token| folder > token))
+                    .collect();
+                folders.dedup();
+
+                let next_page_token = if folders.len() > page_size {
+                    folders.truncate(page_size);
+                    folders.last().cloned()
+                } else {
+                    None
+                };
+
+                Ok((folders, next_page_token))
+            }
+        }
+    }
+}
+
+/// Classify a Firebase error: 404s become `NotFound`, 429s become `QuotaExceeded`,
+/// everything else is an API error
+fn firebase_error(e: cloud_storage::Error) -> ObjectStoreError {
+    let err_str = e.to_string();
+    if err_str.contains("429") || err_str.contains("RATE_LIMIT_EXCEEDED") {
+        ObjectStoreError::QuotaExceeded {
+            retry_after_secs: retry_after_secs(&err_str).unwrap_or(DEFAULT_RETRY_AFTER_SECS),
+        }
+    } else if err_str.contains("404")
+        || err_str.contains("not found")
+        || err_str.contains("No such object")
+    {
+        ObjectStoreError::NotFound(err_str)
+    } else {
+        ObjectStoreError::Api(err_str)
+    }
+}
+
+/// cloud-storage doesn't expose response headers, so pick up a `Retry-After: N`
+/// value only if it made it into the error text
+fn retry_after_secs(err_str: &str) -> Option<u64> {
+    let lower = err_str.to_ascii_lowercase();
+    let rest = &lower[lower.find("retry-after")? + "retry-after".len()..];
+    let digits: String = rest
+        .trim_start_matches(|c: char| c == ':' || c == '"' || c.is_whitespace())
+        .chars()
+        .take_while(|c| c.is_ascii_digit())
+        .collect();
+    digits.parse().ok()
+}
@@ -0,0 +1,66 @@
+//! Per-user image quotas: how many images a user may keep and how many bytes they may use
+
+use crate::registration::error::RegistrationError;
+use crate::registration::image_storage::{MAX_IMAGE_BYTES, MAX_SAMPLE_IMAGES};
+use serde::{Deserialize, Serialize};
+
+/// Prefix of the `ValidationError` returned when an upload would exceed a quota
+pub const QUOTA_EXCEEDED_MESSAGE: &str = "Image quota exceeded";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QuotaConfig {
+    pub max_images_per_user: usize,
+    pub max_total_bytes_per_user: u64,
+}
+
+impl Default for QuotaConfig {
+    fn default() -> Self {
+        Self {
+            max_images_per_user: MAX_SAMPLE_IMAGES,
+            max_total_bytes_per_user: (MAX_SAMPLE_IMAGES * MAX_IMAGE_BYTES) as u64,
+        }
+    }
+}
+
+/// A user's current image usage, from listing their images folder
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct UsageStats {
+    pub image_count: usize,
+    pub total_bytes: u64,
+}
+
+impl QuotaConfig {
+    /// Check that one more image of `new_bytes` fits within both limits
+    pub fn check_upload(&self, usage: &UsageStats, new_bytes: u64) -> Result<(), RegistrationError> {
+        self.check_batch(usage, 1, new_bytes)
+    }
+
+    /// Check that `new_images` more images totalling `new_bytes` fit within both limits
+    pub fn check_batch(
+        &self,
+        usage: &UsageStats,
+        new_images: usize,
+        new_bytes: u64,
+    ) -> Result<(), RegistrationError> {
+        if usage.image_count + new_images > self.max_images_per_user {
+            return Err(RegistrationError::ValidationError(format!(
+                "{}: {} images stored + {} new (max {})",
+                QUOTA_EXCEEDED_MESSAGE, usage.image_count, new_images, self.max_images_per_user
+            )));
+        }
+
+        if usage.total_bytes + new_bytes > self.max_total_bytes_per_user {
+            return Err(RegistrationError::ValidationError(format!(
+                "{}: {} + {} bytes exceeds {} bytes",
+                QUOTA_EXCEEDED_MESSAGE, usage.total_bytes, new_bytes, self.max_total_bytes_per_user
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+/// Whether `e` came from `QuotaConfig::check_upload` (the API answers these with 429)
+pub fn is_quota_exceeded(e: &RegistrationError) -> bool {
+    matches!(e, RegistrationError::ValidationError(msg) if msg.starts_with(QUOTA_EXCEEDED_MESSAGE))
+}
@@ -0,0 +1,35 @@
+//! Shared fixtures for registration tests: an in-memory directory and small images
+
+use crate::registration::object_store::ObjectStore;
+use crate::registration::{RegistrationConfig, UserDirectory, UserInfo};
+use image::{ImageFormat, Rgba, RgbaImage};
+use std::io::Cursor;
+
+/// Empty directory backed by the in-memory bucket
+pub fn directory() -> UserDirectory {
+    UserDirectory::new_in_memory(RegistrationConfig::default())
+}
+
+/// Register `username` at a loopback address
+pub async fn register(directory: &UserDirectory, username: &str) -> UserInfo {
+    let user = UserInfo::new(username, "127.0.0.1:9000");
+    directory.register_user(&user).await.expect("register test user");
+    user
+}
+
+/// A `size`x`size` PNG filled with a colour derived from `seed`, so different
+/// seeds give different bytes
+pub fn png(seed: u8, size: u32) -> Vec<u8> {
+    let img = RgbaImage::from_pixel(size, size, Rgba([seed, seed.wrapping_mul(7), 255 - seed, 255]));
+    let mut out = Cursor::new(Vec::new());
+    img.write_to(&mut out, ImageFormat::Png).expect("encode test png");
+    out.into_inner()
+}
+
+/// Make every storage call of an in-memory `directory` fail, or work again
+pub fn set_storage_unavailable(directory: &UserDirectory, unavailable: bool) {
+    match directory.store() {
+        ObjectStore::InMemory(bucket) => bucket.set_unavailable(unavailable),
+        ObjectStore::Firebase { .. } => panic!("test directory is not in-memory"),
+    }
+}
@@ -1,16 +1,61 @@
 //! User Directory implementation using Firebase Storage
 //! Structure: users/{username}/profile.json
+//!
+//! Profiles are written gzip-compressed (content type `application/gzip`).
+//! Reads check for the gzip magic bytes, so profiles stored as plain JSON
+//! before compression was added still load.
 
 use crate::registration::auth::FirebaseAuth;
 use crate::registration::config::RegistrationConfig;
 use crate::registration::error::RegistrationError;
+use crate::registration::image_storage::{content_type_for, ImageStorage};
+use crate::registration::object_store::{InMemoryBucket, ObjectStore, ObjectStoreError};
+use crate::registration::quota_manager::QuotaConfig;
 use crate::registration::user_info::UserInfo;
-use cloud_storage::Client;
-use futures::stream::StreamExt;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use futures::future::join_all;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io::{Read, Write};
 use tracing::{info, warn};
 
+/// Outcome of `UserDirectory::rename_user`
+#[derive(Debug, Clone, Serialize)]
+pub struct RenameResult {
+    /// Images and notes copied to the new user folder (the profile is not counted)
+    pub files_moved: usize,
+}
+
+/// Leading bytes of every gzip stream; a JSON profile can never start with them
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+const PROFILE_CONTENT_TYPE: &str = "application/gzip";
+
+/// Serialize a profile as gzip-compressed JSON
+fn encode_profile(user: &UserInfo) -> Result<Vec<u8>, RegistrationError> {
+    let json = serde_json::to_vec(user)?;
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&json)?;
+    Ok(encoder.finish()?)
+}
+
+/// Parse a stored profile, compressed or legacy plain JSON
+fn decode_profile(data: &[u8]) -> Result<UserInfo, RegistrationError> {
+    if !data.starts_with(&GZIP_MAGIC) {
+        return Ok(serde_json::from_slice(data)?);
+    }
+
+    let mut json = Vec::new();
+    GzDecoder::new(data).read_to_end(&mut json).map_err(|e| {
+        RegistrationError::FirebaseApiError(format!("Corrupt compressed profile: {}", e))
+    })?;
+    Ok(serde_json::from_slice(&json)?)
+}
+
 pub struct UserDirectory {
-    client: Client,
+    store: ObjectStore,
     config: RegistrationConfig,
 }
 
@@ -20,7 +65,23 @@ impl UserDirectory {
 
         info!("UserDirectory initialized with bucket: {}", config.bucket_name);
 
-        Ok(Self { client, config })
+        let store = ObjectStore::Firebase {
+            client,
+            bucket: config.bucket_name.clone(),
+        };
+
+        Ok(Self { store, config })
+    }
+
+    /// Create a directory backed by an in-memory bucket (test mode).
+    /// Profiles, images and notes are kept in process memory; no Firebase calls are made.
+    pub fn new_in_memory(config: RegistrationConfig) -> Self {
+        info!("UserDirectory initialized with in-memory storage (test mode)");
+
+        Self {
+            store: ObjectStore::InMemory(InMemoryBucket::default()),
+            config,
+        }
     }
 
     /// Get the profile path for a user
@@ -37,19 +98,13 @@ impl UserDirectory {
     async fn user_exists(&self, username: &str) -> Result<bool, RegistrationError> {
         let profile_path = self.get_profile_path(username);
         
-        match self.client.object().download(&self.config.bucket_name, &profile_path).await {
+        match self.store.download(&profile_path).await {
             Ok(_) => Ok(true),
+            // If 404 or "No such object", user doesn't exist
+            Err(ObjectStoreError::NotFound(_)) => Ok(false),
             Err(e) => {
-                let err_str = e.to_string();
-                // If 404 or "No such object", user doesn't exist
-                if err_str.contains("404") 
-                    || err_str.contains("not found") 
-                    || err_str.contains("No such object") {
-                    Ok(false)
-                } else {
-                    // Real error, propagate it
-                    Err(RegistrationError::FirebaseApiError(format!("Error checking user existence: {}", e)))
-                }
+                // Real error, propagate it
+                Err(e.into_registration_error("Error checking user existence"))
             }
         }
     }
@@ -75,96 +130,138 @@ impl UserDirectory {
             }
         }
 
-        let json_content = serde_json::to_string_pretty(user)?;
+        let profile = encode_profile(user)?;
 
-        self.client
-            .object()
-            .create(
-                &self.config.bucket_name,
-                json_content.as_bytes().to_vec(),
-                &profile_path,
-                "application/json",
-            )
+        self.store
+            .create(&profile_path, profile, PROFILE_CONTENT_TYPE)
             .await
-            .map_err(|e| {
-                RegistrationError::FirebaseApiError(format!("Failed to register user: {}", e))
-            })?;
+            .map_err(|e| e.into_registration_error("Failed to register user"))?;
 
         info!("Registered user '{}' at path: {}", user.username, profile_path);
         Ok(user.id.clone())
     }
 
+    /// Overwrite an existing user's profile (re-registration with `upsert`)
+    pub async fn update_user(&self, user: &UserInfo) -> Result<(), RegistrationError> {
+        user.validate()
+            .map_err(RegistrationError::ValidationError)?;
+
+        if !self.user_exists(&user.username).await? {
+            return Err(RegistrationError::UserNotFound(user.username.clone()));
+        }
+
+        let profile_path = self.get_profile_path(&user.username);
+        self.store
+            .create(&profile_path, encode_profile(user)?, PROFILE_CONTENT_TYPE)
+            .await
+            .map_err(|e| e.into_registration_error("Failed to update user"))?;
+
+        info!("Updated user '{}' at path: {}", user.username, profile_path);
+        Ok(())
+    }
+
 
     pub async fn get_user(&self, username: &str) -> Result<UserInfo, RegistrationError> {
         let profile_path = self.get_profile_path(username);
         
         let content = self
-            .client
-            .object()
-            .download(&self.config.bucket_name, &profile_path)
+            .store
+            .download(&profile_path)
             .await
-            .map_err(|e| {
-                if e.to_string().contains("404") || e.to_string().contains("not found") {
-                    RegistrationError::UserNotFound(username.to_string())
-                } else {
-                    RegistrationError::FirebaseApiError(format!("Failed to download user profile: {}", e))
-                }
+            .map_err(|e| match e {
+                ObjectStoreError::NotFound(_) => RegistrationError::UserNotFound(username.to_string()),
+                e => e.into_registration_error("Failed to download user profile"),
             })?;
 
-        let user: UserInfo = serde_json::from_slice(&content)?;
-        Ok(user)
+        decode_profile(&content)
+    }
+
+    /// Fetch several user profiles concurrently instead of one round-trip at a time
+    pub async fn batch_get_users(
+        &self,
+        usernames: &[String],
+    ) -> HashMap<String, Result<UserInfo, RegistrationError>> {
+        let results = join_all(usernames.iter().map(|username| self.get_user(username))).await;
+
+        usernames.iter().cloned().zip(results).collect()
     }
 
     pub async fn list_users(&self) -> Result<Vec<UserInfo>, RegistrationError> {
-        let stream = self
-            .client
-            .object()
-            .list(&self.config.bucket_name, Default::default())
+        let objects = self
+            .store
+            .list("users/")
             .await
-            .map_err(|e| {
-                RegistrationError::FirebaseApiError(format!("Failed to list users: {}", e))
-            })?;
-
-        tokio::pin!(stream);
+            .map_err(|e| e.into_registration_error("Failed to list users"))?;
 
         let mut users = Vec::new();
 
-        while let Some(result) = stream.next().await {
-            match result {
-                Ok(object_list) => {
-                    for obj in object_list.items {
-                        // Only process profile.json files
-                        if obj.name.starts_with("users/") && obj.name.ends_with("/profile.json") {
-                            match self.get_user_by_path(&obj.name).await {
-                                Ok(user) => users.push(user),
-                                Err(e) => {
-                                    warn!("Failed to read user file {}: {}", obj.name, e);
-                                }
-                            }
-                        }
+        for obj in objects {
+            // Only process profile.json files
+            if obj.name.ends_with("/profile.json") {
+                match self.get_user_by_path(&obj.name).await {
+                    Ok(user) => users.push(user),
+                    Err(e) => {
+                        warn!("Failed to read user file {}: {}", obj.name, e);
                     }
                 }
+            }
+        }
+
+        Ok(users)
+    }
+
+    /// List one page of users without walking the whole bucket.
+    ///
+    /// Pages over the `users/{username}/` folders, so each page downloads at most
+    /// `page_size` profiles. Pass the returned token back to get the next page;
+    /// `None` means there are no more users.
+    pub async fn list_users_paginated(
+        &self,
+        page_token: Option<String>,
+        page_size: usize,
+    ) -> Result<(Vec<UserInfo>, Option<String>), RegistrationError> {
+        let (folders, next_page_token) = self
+            .store
+            .list_folders_page("users/", page_token, page_size)
+            .await
+            .map_err(|e| e.into_registration_error("Failed to list users"))?;
+
+        let profile_paths: Vec<String> = folders
+            .iter()
+            .map(|folder| format!("{}profile.json", folder))
+            .collect();
+        let results = join_all(profile_paths.iter().map(|path| self.get_user_by_path(path))).await;
+
+        let mut users = Vec::new();
+        for (path, result) in profile_paths.iter().zip(results) {
+            match result {
+                Ok(user) => users.push(user),
                 Err(e) => {
-                    warn!("Error during list operation: {}", e);
+                    warn!("Failed to read user file {}: {}", path, e);
                 }
             }
         }
 
-        Ok(users)
+        Ok((users, next_page_token))
+    }
+
+    /// Cheap round-trip to the storage backend (lists at most one user folder)
+    pub async fn check_storage(&self) -> Result<(), RegistrationError> {
+        self.store
+            .list_folders_page("users/", None, 1)
+            .await
+            .map(|_| ())
+            .map_err(|e| e.into_registration_error("Storage check failed"))
     }
 
     async fn get_user_by_path(&self, path: &str) -> Result<UserInfo, RegistrationError> {
         let content = self
-            .client
-            .object()
-            .download(&self.config.bucket_name, path)
+            .store
+            .download(path)
             .await
-            .map_err(|e| {
-                RegistrationError::FirebaseApiError(format!("Failed to download user file: {}", e))
-            })?;
+            .map_err(|e| e.into_registration_error("Failed to download user file"))?;
 
-        let user: UserInfo = serde_json::from_slice(&content)?;
-        Ok(user)
+        decode_profile(&content)
     }
 
     pub async fn find_user_by_username(
@@ -178,28 +275,230 @@ impl UserDirectory {
         }
     }
 
+    /// Move a user and everything under their folder to a new username.
+    ///
+    /// Storage has no server-side rename, so every object is downloaded and
+    /// re-uploaded under `users/{new_username}/` before the old copies are
+    /// deleted. The new profile is written only after every file is copied, and
+    /// the copies are removed again if that fails, so an interrupted rename
+    /// leaves the old user intact and can simply be retried.
+    pub async fn rename_user(
+        &self,
+        old_username: &str,
+        new_username: &str,
+    ) -> Result<RenameResult, RegistrationError> {
+        if !self.user_exists(old_username).await? {
+            return Err(RegistrationError::UserNotFound(old_username.to_string()));
+        }
+        if self.user_exists(new_username).await? {
+            return Err(RegistrationError::UserAlreadyExists(new_username.to_string()));
+        }
+
+        let mut user = self.get_user(old_username).await?;
+        user.username = new_username.to_string();
+        user.validate().map_err(RegistrationError::ValidationError)?;
+
+        let old_folder = self.get_user_folder(old_username);
+        let new_folder = self.get_user_folder(new_username);
+        let old_profile = self.get_profile_path(old_username);
+
+        let objects = self
+            .store
+            .list(&old_folder)
+            .await
+            .map_err(|e| e.into_registration_error("Failed to list user files"))?;
+        let files: Vec<String> = objects
+            .into_iter()
+            .map(|obj| obj.name)
+            .filter(|name| *name != old_profile)
+            .collect();
+
+        let mut copied = Vec::with_capacity(files.len());
+        let copy_result = async {
+            for old_path in &files {
+                let relative = &old_path[old_folder.len()..];
+                let new_path = format!("{}{}", new_folder, relative);
+                let mime_type = if relative.ends_with(".json") {
+                    "application/json"
+                } else {
+                    content_type_for(relative)
+                };
+
+                let data = self
+                    .store
+                    .download(old_path)
+                    .await
+                    .map_err(|e| e.into_registration_error("Failed to download user file"))?;
+                self.store
+                    .create(&new_path, data, mime_type)
+                    .await
+                    .map_err(|e| e.into_registration_error("Failed to copy user file"))?;
+                copied.push(new_path);
+            }
+
+            // Last, so the new user only exists once all its files do
+            self.store
+                .create(
+                    &self.get_profile_path(new_username),
+                    encode_profile(&user)?,
+                    PROFILE_CONTENT_TYPE,
+                )
+                .await
+                .map_err(|e| e.into_registration_error("Failed to write renamed profile"))
+        }
+        .await;
+
+        if let Err(e) = copy_result {
+            for new_path in &copied {
+                if let Err(cleanup) = self.store.delete(new_path).await {
+                    warn!("Failed to remove {} after failed rename: {}", new_path, cleanup);
+                }
+            }
+            return Err(e);
+        }
+
+        for old_path in files.iter().chain(std::iter::once(&old_profile)) {
+            if let Err(e) = self.store.delete(old_path).await {
+                warn!("Failed to delete {} after rename: {}", old_path, e);
+            }
+        }
+
+        info!(
+            "Renamed user '{}' to '{}' ({} files moved)",
+            old_username,
+            new_username,
+            files.len()
+        );
+        Ok(RenameResult {
+            files_moved: files.len(),
+        })
+    }
+
+    /// Delete a user's images and thumbnails, then their profile. The profile is
+    /// kept if any file can't be deleted, so the cleanup can be retried.
+    #[allow(dead_code)] // for the planned delete-user endpoint
     pub async fn delete_user(&self, username: &str) -> Result<(), RegistrationError> {
+        let image_storage = ImageStorage::new(self);
+        image_storage.delete_all_images(username).await?;
+        image_storage.delete_all_thumbnails(username).await?;
+
         let profile_path = self.get_profile_path(username);
 
-        self.client
-            .object()
-            .delete(&self.config.bucket_name, &profile_path)
+        self.store
+            .delete(&profile_path)
             .await
-            .map_err(|e| {
-                RegistrationError::FirebaseApiError(format!("Failed to delete user: {}", e))
-            })?;
+            .map_err(|e| e.into_registration_error("Failed to delete user"))?;
 
         info!("Deleted user: {}", username);
         Ok(())
     }
 
-    /// Get the client for image operations
-    pub fn get_client(&self) -> &Client {
-        &self.client
+    /// Get the object store for image and note operations
+    pub fn store(&self) -> &ObjectStore {
+        &self.store
+    }
+
+    /// Per-user image limits
+    pub fn quota(&self) -> &QuotaConfig {
+        &self.config.quota
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::registration::note_storage::NoteStorage;
+    use crate::registration::test_util::{directory, png, register};
+    use image::ImageFormat;
+
+    #[test]
+    fn profile_round_trips_through_gzip() {
+        let user = UserInfo::new("alice", "127.0.0.1:9000").with_metadata("tag", "blue");
+        let encoded = encode_profile(&user).unwrap();
+        assert!(encoded.starts_with(&GZIP_MAGIC));
+
+        let decoded = decode_profile(&encoded).unwrap();
+        assert_eq!(decoded.id, user.id);
+        assert_eq!(decoded.addr, user.addr);
+        assert_eq!(decoded.metadata, user.metadata);
+    }
+
+    #[test]
+    fn legacy_plain_json_profile_still_decodes() {
+        let user = UserInfo::new("alice", "127.0.0.1:9000");
+        let legacy = serde_json::to_vec(&user).unwrap();
+
+        let decoded = decode_profile(&legacy).unwrap();
+        assert_eq!(decoded.id, user.id);
+        assert_eq!(decoded.username, "alice");
+        assert!(decode_profile(&[GZIP_MAGIC[0], GZIP_MAGIC[1], 0, 0]).is_err());
+    }
+
+    #[tokio::test]
+    async fn in_memory_directory_round_trips_users() {
+        let dir = directory();
+        assert!(matches!(dir.store(), ObjectStore::InMemory(_)));
+
+        let user = register(&dir, "alice").await;
+        let found = dir.find_user_by_username("alice").await.unwrap().unwrap();
+        assert_eq!(found.id, user.id);
+        assert_eq!(found.addr, user.addr);
+        assert!(dir.find_user_by_username("bob").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn rename_moves_everything_to_new_folder() {
+        let dir = directory();
+        register(&dir, "alice").await;
+        let images = ImageStorage::new(&dir);
+        let mut filenames = Vec::new();
+        for seed in 0..3 {
+            let upload = images
+                .upload_image("alice", png(seed, 16), ImageFormat::Png)
+                .await
+                .unwrap();
+            filenames.push(upload.filename);
+        }
+        NoteStorage::new(&dir).add_note("alice", &filenames[0], 1).await.unwrap();
+        let before = dir.store().list("users/alice/").await.unwrap().len();
+
+        let result = dir.rename_user("alice", "alicia").await.unwrap();
+        // everything but the profile
+        assert_eq!(result.files_moved, before - 1);
+
+        assert!(dir.store().list("users/alice/").await.unwrap().is_empty());
+        assert!(dir.find_user_by_username("alice").await.unwrap().is_none());
+        assert_eq!(dir.store().list("users/alicia/").await.unwrap().len(), before);
+        assert_eq!(dir.get_user("alicia").await.unwrap().username, "alicia");
+
+        let mut moved = images.list_images("alicia").await.unwrap();
+        moved.sort();
+        filenames.sort();
+        assert_eq!(moved, filenames);
+        assert_eq!(NoteStorage::new(&dir).get_notes("alicia").await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn rename_can_be_retried_over_leftover_copies() {
+        let dir = directory();
+        register(&dir, "alice").await;
+        let upload = ImageStorage::new(&dir)
+            .upload_image("alice", png(1, 16), ImageFormat::Png)
+            .await
+            .unwrap();
+
+        // What an interrupted rename leaves behind: some copied files, no profile
+        dir.store()
+            .create(&format!("users/alicia/images/{}", upload.filename), png(1, 16), "image/png")
+            .await
+            .unwrap();
+        assert!(dir.find_user_by_username("alicia").await.unwrap().is_none());
 
-    /// Get the bucket name
-    pub fn get_bucket_name(&self) -> &str {
-        &self.config.bucket_name
+        dir.rename_user("alice", "alicia").await.unwrap();
+        assert_eq!(
+            ImageStorage::new(&dir).list_images("alicia").await.unwrap(),
+            vec![upload.filename]
+        );
+        assert!(dir.find_user_by_username("alice").await.unwrap().is_none());
     }
 }
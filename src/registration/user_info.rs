@@ -42,9 +42,6 @@ impl UserInfo {
         self
     }
 
-    pub fn filename(&self) -> String {
-        format!("user-{}.json", self.id)
-    }
     
     pub fn validate(&self) -> Result<(), String> {
         if self.username.is_empty() {
@@ -55,12 +52,25 @@ impl UserInfo {
         }
         // Basic IP:port validation using SocketAddr
         if self.addr.parse::<std::net::SocketAddr>().is_err() {
-            return Err("Address must be a valid IP:port (e.g., 192.168.1.10:8080)".to_string());
+            return Err(
+                "Address must be a valid IP:port (e.g., 192.168.1.10:8080 or [2001:db8::1]:8080)"
+                    .to_string(),
+            );
         }
         Ok(())
     }
 }
 
+/// Canonical `ip:port` form of a client address, with IPv6 hosts bracketed
+/// (`[::1]:9000`) so it can be dropped straight into a URL.
+/// Addresses that don't parse are returned unchanged for validate() to reject.
+pub fn canonical_addr(addr: &str) -> String {
+    addr.trim()
+        .parse::<std::net::SocketAddr>()
+        .map(|parsed| parsed.to_string())
+        .unwrap_or_else(|_| addr.to_string())
+}
+
 impl std::fmt::Display for UserStatus {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -70,3 +80,25 @@ impl std::fmt::Display for UserStatus {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn canonical_addr_brackets_and_compresses_ipv6() {
+        assert_eq!(canonical_addr(" [2001:db8:0:0:0:0:0:1]:8080 "), "[2001:db8::1]:8080");
+        assert_eq!(canonical_addr("10.0.0.5:9000"), "10.0.0.5:9000");
+        // unparseable input is left for validate() to reject
+        assert_eq!(canonical_addr("2001:db8::1:8080"), "2001:db8::1:8080");
+    }
+
+    #[test]
+    fn validate_accepts_ipv6_user() {
+        let user = UserInfo::new("alice", canonical_addr("[2001:db8::1]:8080"));
+        assert_eq!(user.validate(), Ok(()));
+
+        let unbracketed = UserInfo::new("alice", "2001:db8::1:8080");
+        assert!(unbracketed.validate().is_err());
+    }
+}
@@ -0,0 +1,237 @@
+//! Server settings: app.toml with environment variable overrides
+//!
+//! Every field can be set in app.toml; the matching env var (listed on each
+//! field) wins when present. A missing app.toml just means defaults + env.
+//! Secrets (ADMIN_TOKEN, DOWNLOAD_TOKEN_SECRET) stay env-only.
+
+use crate::registration::QuotaConfig;
+use anyhow::Context;
+use serde::Deserialize;
+use std::net::IpAddr;
+use std::path::Path;
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Settings {
+    /// FIREBASE_BUCKET - required unless test_mode
+    pub firebase_bucket: Option<String>,
+    /// GOOGLE_APPLICATION_CREDENTIALS
+    pub credentials_path: String,
+    /// API_PORT - ignored when http_port is set in the cluster config
+    pub api_port: u16,
+    /// TEST_MODE - in-memory storage, no Firebase calls
+    pub test_mode: bool,
+    /// DISABLE_COMPRESSION
+    pub disable_compression: bool,
+    /// REQUIRE_REQUEST_SIGNATURES
+    pub require_request_signatures: bool,
+    /// REQUIRE_DOWNLOAD_TOKENS
+    pub require_download_tokens: bool,
+    /// HEARTBEAT_TTL_SECONDS - a client is online while its last heartbeat is at most this old
+    pub heartbeat_ttl_secs: u64,
+    /// VERIFY_CLIENT_ADDR - ping the client's /p2p/ping before accepting /register
+    pub verify_client_addr: bool,
+    /// MAX_IMAGES_PER_USER
+    pub max_images_per_user: usize,
+    /// MAX_STORAGE_BYTES_PER_USER - total size of a user's stored images
+    pub max_storage_bytes_per_user: u64,
+    /// TRUSTED_PROXIES (comma-separated) - reverse proxies whose X-Forwarded-For is believed
+    pub trusted_proxies: Vec<IpAddr>,
+    /// ADMIN_TOKEN - env only; admin endpoints are disabled when unset
+    #[serde(skip)]
+    pub admin_token: Option<String>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            firebase_bucket: None,
+            credentials_path: "credentials/firebase-storage.json".to_string(),
+            api_port: 3000,
+            test_mode: false,
+            disable_compression: false,
+            require_request_signatures: false,
+            require_download_tokens: false,
+            heartbeat_ttl_secs: 30,
+            trusted_proxies: Vec::new(),
+            verify_client_addr: false,
+            max_images_per_user: QuotaConfig::default().max_images_per_user,
+            max_storage_bytes_per_user: QuotaConfig::default().max_total_bytes_per_user,
+            admin_token: None,
+        }
+    }
+}
+
+impl Settings {
+    /// Image quota built from the max_* settings
+    pub fn quota(&self) -> QuotaConfig {
+        QuotaConfig {
+            max_images_per_user: self.max_images_per_user,
+            max_total_bytes_per_user: self.max_storage_bytes_per_user,
+        }
+    }
+
+    /// Load `path` (if it exists), then apply env overrides
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        Self::load_with(path.as_ref(), |key| std::env::var(key).ok())
+    }
+
+    /// `load` with env lookups going through `var`
+    fn load_with(path: &Path, var: impl Fn(&str) -> Option<String>) -> anyhow::Result<Self> {
+        let mut settings: Settings = if path.exists() {
+            let text = std::fs::read_to_string(path)
+                .with_context(|| format!("read settings file {}", path.display()))?;
+            toml::from_str(&text)
+                .with_context(|| format!("parse settings file {}", path.display()))?
+        } else {
+            Settings::default()
+        };
+
+        settings.apply_env(var)?;
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    /// Reject combinations that would leave a security setting toothless
+    fn validate(&self) -> anyhow::Result<()> {
+        // /image_token hands a token to anyone who asks unless requests are signed
+        if self.require_download_tokens && !self.require_request_signatures {
+            anyhow::bail!("REQUIRE_DOWNLOAD_TOKENS needs REQUIRE_REQUEST_SIGNATURES, or anyone can request a token");
+        }
+        Ok(())
+    }
+
+    fn apply_env(&mut self, var: impl Fn(&str) -> Option<String>) -> anyhow::Result<()> {
+        if let Some(bucket) = var("FIREBASE_BUCKET") {
+            self.firebase_bucket = Some(bucket);
+        }
+        if let Some(path) = var("GOOGLE_APPLICATION_CREDENTIALS") {
+            self.credentials_path = path;
+        }
+        if let Some(port) = var("API_PORT") {
+            self.api_port = port
+                .parse()
+                .with_context(|| format!("API_PORT must be a port number, got '{}'", port))?;
+        }
+        if let Some(value) = var("TEST_MODE") {
+            self.test_mode = is_truthy(&value);
+        }
+        if let Some(value) = var("DISABLE_COMPRESSION") {
+            self.disable_compression = is_truthy(&value);
+        }
+        if let Some(value) = var("REQUIRE_REQUEST_SIGNATURES") {
+            self.require_request_signatures = is_truthy(&value);
+        }
+        if let Some(value) = var("REQUIRE_DOWNLOAD_TOKENS") {
+            self.require_download_tokens = is_truthy(&value);
+        }
+        if let Some(ttl) = var("HEARTBEAT_TTL_SECONDS") {
+            self.heartbeat_ttl_secs = ttl.parse().with_context(|| {
+                format!("HEARTBEAT_TTL_SECONDS must be a number of seconds, got '{}'", ttl)
+            })?;
+        }
+        if let Some(value) = var("VERIFY_CLIENT_ADDR") {
+            self.verify_client_addr = is_truthy(&value);
+        }
+        if let Some(max) = var("MAX_IMAGES_PER_USER") {
+            self.max_images_per_user = max
+                .parse()
+                .with_context(|| format!("MAX_IMAGES_PER_USER must be a number, got '{}'", max))?;
+        }
+        if let Some(max) = var("MAX_STORAGE_BYTES_PER_USER") {
+            self.max_storage_bytes_per_user = max.parse().with_context(|| {
+                format!("MAX_STORAGE_BYTES_PER_USER must be a number of bytes, got '{}'", max)
+            })?;
+        }
+        if let Some(proxies) = var("TRUSTED_PROXIES") {
+            self.trusted_proxies = proxies
+                .split(',')
+                .map(str::trim)
+                .filter(|p| !p.is_empty())
+                .map(|p| {
+                    p.parse()
+                        .with_context(|| format!("TRUSTED_PROXIES entry '{}' is not an IP address", p))
+                })
+                .collect::<anyhow::Result<_>>()?;
+        }
+        self.admin_token = var("ADMIN_TOKEN").filter(|token| !token.is_empty());
+        Ok(())
+    }
+}
+
+/// Env flags are on for "true" or "1", matching the previous env-only behaviour
+fn is_truthy(value: &str) -> bool {
+    matches!(value, "true" | "1")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn settings_file(contents: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("app-{}.toml", uuid::Uuid::new_v4()));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn env(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars: HashMap<String, String> =
+            vars.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        move |key| vars.get(key).cloned()
+    }
+
+    #[test]
+    fn file_values_load_and_env_overrides_win() {
+        let path = settings_file(
+            r#"
+            api_port = 4000
+            heartbeat_ttl_secs = 60
+            require_request_signatures = true
+            trusted_proxies = ["10.0.0.1"]
+            "#,
+        );
+
+        let from_file = Settings::load_with(&path, env(&[])).unwrap();
+        assert_eq!(from_file.api_port, 4000);
+        assert_eq!(from_file.heartbeat_ttl_secs, 60);
+        assert!(from_file.require_request_signatures);
+        assert_eq!(from_file.trusted_proxies, vec!["10.0.0.1".parse::<IpAddr>().unwrap()]);
+        // untouched fields keep their defaults
+        assert_eq!(from_file.max_images_per_user, Settings::default().max_images_per_user);
+
+        let overridden = Settings::load_with(
+            &path,
+            env(&[("API_PORT", "5000"), ("REQUIRE_REQUEST_SIGNATURES", "false"), ("ADMIN_TOKEN", "secret")]),
+        )
+        .unwrap();
+        assert_eq!(overridden.api_port, 5000);
+        assert!(!overridden.require_request_signatures);
+        assert_eq!(overridden.heartbeat_ttl_secs, 60);
+        assert_eq!(overridden.admin_token.as_deref(), Some("secret"));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn missing_file_means_defaults_and_bad_env_is_an_error() {
+        let missing = std::env::temp_dir().join(format!("missing-{}.toml", uuid::Uuid::new_v4()));
+        let settings = Settings::load_with(&missing, env(&[])).unwrap();
+        assert_eq!(settings.api_port, Settings::default().api_port);
+
+        assert!(Settings::load_with(&missing, env(&[("API_PORT", "not-a-port")])).is_err());
+    }
+
+    #[test]
+    fn download_tokens_require_request_signatures() {
+        let missing = std::env::temp_dir().join(format!("missing-{}.toml", uuid::Uuid::new_v4()));
+        assert!(Settings::load_with(&missing, env(&[("REQUIRE_DOWNLOAD_TOKENS", "true")])).is_err());
+
+        let settings = Settings::load_with(
+            &missing,
+            env(&[("REQUIRE_DOWNLOAD_TOKENS", "true"), ("REQUIRE_REQUEST_SIGNATURES", "true")]),
+        )
+        .unwrap();
+        assert!(settings.require_download_tokens);
+    }
+}
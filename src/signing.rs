@@ -0,0 +1,306 @@
+//! HMAC-SHA256 request signing for mutating API calls
+//!
+//! A client holds a secret key and registers `key_hash = hex(SHA-256(secret))`,
+//! which is stored in its profile metadata. Every POST/PUT/DELETE then carries
+//! `X-Request-Timestamp: {unix seconds}` and
+//! `X-Request-Signature: hex(HMAC-SHA256(key_hash_bytes, username + ":" + timestamp + ":" + body))`.
+//! Timestamps more than `MAX_SIGNATURE_AGE_SECS` away from the server clock are
+//! rejected, so a captured request can't be replayed later. A signed request for
+//! a route naming a user in its path (`/users/:username/...`) must be signed by
+//! that user.
+//! Verification is only enforced when require_request_signatures is set
+//! (REQUIRE_REQUEST_SIGNATURES=true or app.toml).
+//!
+//! Also issues short-lived download tokens: `{expiry}.{hex HMAC}` scoped to one
+//! username + filename, so a client can hand an image URL to a browser without
+//! exposing the rest of the API.
+
+use crate::api::{storage_error_response, AppState};
+use crate::registration::RegistrationError;
+use axum::{
+    body::{to_bytes, Body},
+    extract::{Path, Request, State},
+    http::{HeaderMap, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Json, Response},
+};
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::sync::OnceLock;
+use tracing::{info, warn};
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub const SIGNATURE_HEADER: &str = "X-Request-Signature";
+pub const TIMESTAMP_HEADER: &str = "X-Request-Timestamp";
+pub const USERNAME_HEADER: &str = "X-Username";
+
+/// Profile metadata key holding the user's signing key hash
+pub const KEY_HASH_METADATA: &str = "key_hash";
+
+/// How far a request's timestamp may be from the server clock, either way
+pub const MAX_SIGNATURE_AGE_SECS: i64 = 300;
+
+/// Largest body buffered for signature checks (sample image updates can be a few MB)
+const MAX_SIGNED_BODY_BYTES: usize = 8 * 1024 * 1024;
+
+/// Default and maximum lifetime of a download token
+pub const DEFAULT_DOWNLOAD_TOKEN_TTL_SECS: u64 = 300;
+pub const MAX_DOWNLOAD_TOKEN_TTL_SECS: u64 = 3600;
+
+/// A key hash is the hex SHA-256 of the client's secret: 64 hex characters
+pub fn is_valid_key_hash(key_hash: &str) -> bool {
+    key_hash.len() == 64 && key_hash.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+fn request_mac(key: &[u8], username: &str, timestamp: i64, body: &[u8]) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(username.as_bytes());
+    mac.update(b":");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b":");
+    mac.update(body);
+    mac
+}
+
+/// Hex signature a client sends for `body` at `timestamp`
+#[cfg(test)]
+pub fn sign(key: &[u8], username: &str, timestamp: i64, body: &[u8]) -> String {
+    hex::encode(request_mac(key, username, timestamp, body).finalize().into_bytes())
+}
+
+/// Constant-time check of a hex signature
+pub fn verify(key: &[u8], username: &str, timestamp: i64, body: &[u8], signature_hex: &str) -> bool {
+    let Ok(signature) = hex::decode(signature_hex) else {
+        return false;
+    };
+    request_mac(key, username, timestamp, body).verify_slice(&signature).is_ok()
+}
+
+/// Check the signature and timestamp headers of a request by `username`
+/// against their key; `Err` says what was wrong
+pub fn check_request(
+    headers: &HeaderMap,
+    key: &[u8],
+    username: &str,
+    body: &[u8],
+    now_unix: i64,
+) -> Result<(), String> {
+    let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+
+    let Some(signature) = header(SIGNATURE_HEADER) else {
+        return Err(format!("Missing {} header", SIGNATURE_HEADER));
+    };
+    let Some(timestamp) = header(TIMESTAMP_HEADER).and_then(|t| t.parse::<i64>().ok()) else {
+        return Err(format!("Missing or invalid {} header", TIMESTAMP_HEADER));
+    };
+    if (now_unix - timestamp).abs() > MAX_SIGNATURE_AGE_SECS {
+        return Err(format!(
+            "{} is more than {}s from server time",
+            TIMESTAMP_HEADER, MAX_SIGNATURE_AGE_SECS
+        ));
+    }
+    if !verify(key, username, timestamp, body, signature) {
+        return Err("Invalid request signature".to_string());
+    }
+    Ok(())
+}
+
+fn unauthorized(message: impl Into<String>) -> Response {
+    (
+        StatusCode::UNAUTHORIZED,
+        Json(serde_json::json!({ "message": message.into() })),
+    )
+        .into_response()
+}
+
+/// Middleware verifying `X-Request-Signature` on mutating requests.
+///
+/// The signing user is the JSON body's `username` field, or the `X-Username`
+/// header for requests without one (multipart uploads, notes). `/register`
+/// is exempt because the user has no stored key yet, and `/admin/*` and
+/// `/register/bulk` are authenticated by `X-Admin-Token` instead.
+/// Installed with `route_layer`, so the matched route's `:username` is known.
+pub async fn verify_request_signature(
+    State(state): State<AppState>,
+    path_params: Option<Path<HashMap<String, String>>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let is_mutating = matches!(
+        *request.method(),
+        Method::POST | Method::PUT | Method::DELETE
+    );
+    let path = request.uri().path();
+    let exempt = path == "/register" || path == "/register/bulk" || path.starts_with("/admin/");
+    if !is_mutating || exempt {
+        return next.run(request).await;
+    }
+
+    let (parts, body) = request.into_parts();
+    let bytes = match to_bytes(body, MAX_SIGNED_BODY_BYTES).await {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            return (
+                StatusCode::PAYLOAD_TOO_LARGE,
+                Json(serde_json::json!({ "message": format!("Request body too large: {}", e) })),
+            )
+                .into_response();
+        }
+    };
+
+    let body_username = serde_json::from_slice::<serde_json::Value>(&bytes)
+        .ok()
+        .and_then(|v| v.get("username").and_then(|u| u.as_str()).map(str::to_string));
+    let header_username = parts
+        .headers
+        .get(USERNAME_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+
+    let Some(username) = body_username.or(header_username) else {
+        return unauthorized(format!(
+            "Cannot determine signing user (send a username field or {} header)",
+            USERNAME_HEADER
+        ));
+    };
+
+    // A valid signature only vouches for the signer's own data
+    if let Some(Path(params)) = &path_params {
+        if let Some(path_username) = params.get("username") {
+            if *path_username != username {
+                info!(
+                    "Rejected request to {} - signed by '{}' for '{}'",
+                    parts.uri.path(),
+                    username,
+                    path_username
+                );
+                return (
+                    StatusCode::FORBIDDEN,
+                    Json(serde_json::json!({
+                        "message": format!("Signed by '{}' but the path names '{}'", username, path_username)
+                    })),
+                )
+                    .into_response();
+            }
+        }
+    }
+
+    let user = match state.user_directory.get_user(&username).await {
+        Ok(user) => user,
+        Err(RegistrationError::UserNotFound(_)) => {
+            return unauthorized(format!("Unknown user '{}'", username));
+        }
+        Err(e) => {
+            tracing::error!("Failed to load user for signature check: {}", e);
+            return storage_error_response(
+                &e,
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(serde_json::json!({ "message": format!("Failed to verify signature: {}", e) })),
+            );
+        }
+    };
+
+    let Some(key_hash) = user.metadata.get(KEY_HASH_METADATA) else {
+        return unauthorized(format!("User '{}' has no signing key registered", username));
+    };
+    let Ok(key) = hex::decode(key_hash) else {
+        warn!("Stored key hash for '{}' is not valid hex", username);
+        return unauthorized(format!("User '{}' has an invalid signing key", username));
+    };
+
+    let now = chrono::Utc::now().timestamp();
+    if let Err(message) = check_request(&parts.headers, &key, &username, &bytes, now) {
+        info!("Rejected request to {} - {} for '{}'", parts.uri.path(), message, username);
+        return unauthorized(message);
+    }
+
+    next.run(Request::from_parts(parts, Body::from(bytes))).await
+}
+
+/// Key for download tokens: DOWNLOAD_TOKEN_SECRET, or a random per-process key.
+/// Set the env var to the same value on every node so tokens survive a leader change.
+fn download_token_key() -> &'static [u8] {
+    static KEY: OnceLock<Vec<u8>> = OnceLock::new();
+    KEY.get_or_init(|| match std::env::var("DOWNLOAD_TOKEN_SECRET") {
+        Ok(secret) if !secret.is_empty() => secret.into_bytes(),
+        _ => {
+            warn!("DOWNLOAD_TOKEN_SECRET not set; download tokens are only valid on this node until restart");
+            rand::thread_rng().gen::<[u8; 32]>().to_vec()
+        }
+    })
+}
+
+fn download_token_mac(username: &str, filename: &str, expires_at: i64) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(download_token_key())
+        .expect("HMAC accepts keys of any length");
+    mac.update(username.as_bytes());
+    mac.update(b"/");
+    mac.update(filename.as_bytes());
+    mac.update(b":");
+    mac.update(expires_at.to_string().as_bytes());
+    mac
+}
+
+/// Issue a token for `username`/`filename` expiring at `expires_at` (unix seconds)
+pub fn issue_download_token(username: &str, filename: &str, expires_at: i64) -> String {
+    let tag = download_token_mac(username, filename, expires_at)
+        .finalize()
+        .into_bytes();
+    format!("{}.{}", expires_at, hex::encode(tag))
+}
+
+/// Check a download token against the requested image and the current time
+pub fn verify_download_token(token: &str, username: &str, filename: &str) -> Result<(), String> {
+    let (expiry, tag_hex) = token
+        .split_once('.')
+        .ok_or_else(|| "Malformed download token".to_string())?;
+    let expires_at: i64 = expiry
+        .parse()
+        .map_err(|_| "Malformed download token".to_string())?;
+    let tag = hex::decode(tag_hex).map_err(|_| "Malformed download token".to_string())?;
+
+    download_token_mac(username, filename, expires_at)
+        .verify_slice(&tag)
+        .map_err(|_| "Download token is not valid for this image".to_string())?;
+
+    if chrono::Utc::now().timestamp() > expires_at {
+        return Err("Download token has expired".to_string());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn in_an_hour() -> i64 {
+        chrono::Utc::now().timestamp() + 3600
+    }
+
+    #[test]
+    fn download_token_is_valid_for_its_image() {
+        let token = issue_download_token("alice", "a.png", in_an_hour());
+        assert_eq!(verify_download_token(&token, "alice", "a.png"), Ok(()));
+    }
+
+    #[test]
+    fn expired_download_token_is_rejected() {
+        let expired = chrono::Utc::now().timestamp() - 1;
+        let token = issue_download_token("alice", "a.png", expired);
+        assert_eq!(
+            verify_download_token(&token, "alice", "a.png"),
+            Err("Download token has expired".to_string())
+        );
+    }
+
+    #[test]
+    fn download_token_for_another_image_is_rejected() {
+        let token = issue_download_token("alice", "a.png", in_an_hour());
+        assert!(verify_download_token(&token, "alice", "b.png").is_err());
+        assert!(verify_download_token(&token, "bob", "a.png").is_err());
+        assert!(verify_download_token("not-a-token", "alice", "a.png").is_err());
+    }
+}
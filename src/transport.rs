@@ -0,0 +1,200 @@
+//! Election peer-to-peer transport: plain TCP, or mutual TLS when configured
+//!
+//! With `[tls]` set in config.toml every node presents a certificate signed by
+//! the shared cluster CA and only accepts peers that do the same, so a host
+//! without a cluster cert can't inject Heartbeat/LeaderAnnounce messages.
+
+use anyhow::Context;
+use serde::Deserialize;
+use std::fs::File;
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::sync::{Arc, OnceLock};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore, ServerConfig};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+/// `[tls]` section of config.toml (PEM file paths)
+#[derive(Deserialize, Debug, Clone)]
+pub struct TlsConfig {
+    /// CA certificate that signed every node's certificate
+    pub ca_cert: String,
+    /// This node's certificate chain
+    pub cert: String,
+    /// This node's private key
+    pub key: String,
+    /// Name to verify peer certificates against; defaults to the peer's IP,
+    /// which then has to be in each node certificate's subjectAltName
+    #[serde(default)]
+    pub server_name: Option<String>,
+}
+
+/// Any byte stream the election protocol can run over
+pub trait PeerStream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> PeerStream for T {}
+
+pub type BoxedStream = Box<dyn PeerStream>;
+
+pub struct ElectionTls {
+    acceptor: TlsAcceptor,
+    connector: TlsConnector,
+    server_name: Option<String>,
+}
+
+static ELECTION_TLS: OnceLock<ElectionTls> = OnceLock::new();
+
+impl ElectionTls {
+    pub fn from_config(cfg: &TlsConfig) -> anyhow::Result<Self> {
+        let mut roots = RootCertStore::empty();
+        for cert in load_certs(&cfg.ca_cert)? {
+            roots.add(cert).context("add CA certificate")?;
+        }
+        let roots = Arc::new(roots);
+
+        let certs = load_certs(&cfg.cert)?;
+        let key = load_key(&cfg.key)?;
+
+        let client_verifier = WebPkiClientVerifier::builder(roots.clone())
+            .build()
+            .context("build peer certificate verifier")?;
+        let server_config = ServerConfig::builder()
+            .with_client_cert_verifier(client_verifier)
+            .with_single_cert(certs.clone(), key.clone_key())
+            .context("build TLS server config")?;
+
+        let client_config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_client_auth_cert(certs, key)
+            .context("build TLS client config")?;
+
+        Ok(Self {
+            acceptor: TlsAcceptor::from(Arc::new(server_config)),
+            connector: TlsConnector::from(Arc::new(client_config)),
+            server_name: cfg.server_name.clone(),
+        })
+    }
+
+    /// TLS handshake on an accepted connection; fails unless the peer presents a cluster cert
+    async fn accept(&self, stream: TcpStream) -> anyhow::Result<BoxedStream> {
+        let tls_stream = self.acceptor.accept(stream).await.context("TLS handshake")?;
+        Ok(Box::new(tls_stream))
+    }
+
+    /// TLS handshake on an outgoing connection to `peer`
+    async fn connect(&self, peer: &SocketAddr, stream: TcpStream) -> anyhow::Result<BoxedStream> {
+        let server_name = match &self.server_name {
+            Some(name) => ServerName::try_from(name.clone())
+                .with_context(|| format!("invalid TLS server_name '{}'", name))?,
+            None => ServerName::IpAddress(peer.ip().into()),
+        };
+        let tls_stream = self
+            .connector
+            .connect(server_name, stream)
+            .await
+            .with_context(|| format!("TLS handshake with {}", peer))?;
+        Ok(Box::new(tls_stream))
+    }
+}
+
+/// Turn on TLS for all election traffic in this process (call once at startup)
+pub fn enable_tls(tls: ElectionTls) -> anyhow::Result<()> {
+    ELECTION_TLS
+        .set(tls)
+        .map_err(|_| anyhow::anyhow!("election TLS already configured"))
+}
+
+/// Wrap an accepted connection, performing the TLS handshake if enabled
+pub async fn accept(stream: TcpStream) -> anyhow::Result<BoxedStream> {
+    match ELECTION_TLS.get() {
+        Some(tls) => tls.accept(stream).await,
+        None => Ok(Box::new(stream)),
+    }
+}
+
+/// Connect to a peer, performing the TLS handshake if enabled
+pub async fn connect(peer: &SocketAddr) -> anyhow::Result<BoxedStream> {
+    let stream = TcpStream::connect(peer).await?;
+    match ELECTION_TLS.get() {
+        Some(tls) => tls.connect(peer, stream).await,
+        None => Ok(Box::new(stream)),
+    }
+}
+
+fn load_certs(path: &str) -> anyhow::Result<Vec<CertificateDer<'static>>> {
+    let file = File::open(path).with_context(|| format!("open certificate file {}", path))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("parse certificates in {}", path))?;
+    if certs.is_empty() {
+        anyhow::bail!("no certificates found in {}", path);
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &str) -> anyhow::Result<PrivateKeyDer<'static>> {
+    let file = File::open(path).with_context(|| format!("open key file {}", path))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .with_context(|| format!("parse private key in {}", path))?
+        .ok_or_else(|| anyhow::anyhow!("no private key found in {}", path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::framing;
+    use tokio::net::TcpListener;
+
+    /// TLS settings from the fixtures in testdata/tls; `node` names the cert/key pair
+    fn fixture_tls(node: &str) -> ElectionTls {
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/tls");
+        ElectionTls::from_config(&TlsConfig {
+            ca_cert: format!("{}/cluster-ca.pem", dir),
+            cert: format!("{}/{}.pem", dir, node),
+            key: format!("{}/{}.key", dir, node),
+            server_name: None,
+        })
+        .unwrap()
+    }
+
+    /// Accept one connection on `server` while `client` connects and sends a ping;
+    /// returns what the server read, or its handshake error
+    async fn exchange(server: ElectionTls, client: ElectionTls) -> anyhow::Result<Option<String>> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let accepting = async {
+            let (stream, _) = listener.accept().await?;
+            let mut stream = server.accept(stream).await?;
+            framing::read_frame::<_, String>(&mut stream).await
+        };
+        let connecting = async {
+            let stream = TcpStream::connect(addr).await?;
+            let mut stream = client.connect(&addr, stream).await?;
+            framing::write_frame(&mut stream, &"ping".to_string()).await?;
+            // Keep the connection open until the server has read (or rejected) it
+            let _ = framing::read_frame::<_, String>(&mut stream).await;
+            anyhow::Ok(())
+        };
+
+        let (received, _) = tokio::join!(
+            accepting,
+            tokio::time::timeout(std::time::Duration::from_secs(5), connecting)
+        );
+        received
+    }
+
+    #[tokio::test]
+    async fn cluster_nodes_talk_over_mutual_tls() {
+        let received = exchange(fixture_tls("node"), fixture_tls("node")).await.unwrap();
+        assert_eq!(received.as_deref(), Some("ping"));
+    }
+
+    #[tokio::test]
+    async fn peer_without_cluster_cert_is_rejected() {
+        let err = exchange(fixture_tls("node"), fixture_tls("intruder")).await.unwrap_err();
+        assert!(format!("{:#}", err).contains("TLS handshake"), "{:#}", err);
+    }
+}